@@ -1,6 +1,8 @@
+#![allow(clippy::redundant_field_names)]
+
 #[macro_use] extern crate serde_derive;
 extern crate serde;
-#[macro_use] extern crate serde_json;
+#[cfg_attr(test, macro_use)] extern crate serde_json;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(untagged)]
@@ -26,7 +28,7 @@ pub struct Request {
 }
 
 impl Request {
-    fn new(method: String, params: serde_json::Value, id: Id) -> Request {
+    pub fn new(method: String, params: serde_json::Value, id: Id) -> Request {
         Request {
             jsonrpc: From::from("2.0"),
             method: method,
@@ -34,7 +36,7 @@ impl Request {
             id: Some(id)
         }
     }
-    fn new_notification(method: String, params: serde_json::Value) -> Request {
+    pub fn new_notification(method: String, params: serde_json::Value) -> Request {
         Request {
             jsonrpc: From::from("2.0"),
             method: method,
@@ -42,7 +44,7 @@ impl Request {
             id: None
         }
     }
-    fn is_notification(&self) -> bool {
+    pub fn is_notification(&self) -> bool {
         self.id.is_none()
     }
 }
@@ -55,7 +57,7 @@ pub struct Response {
 }
 
 impl Response {
-    fn is_error(&self) -> bool {
+    pub fn is_error(&self) -> bool {
         self.error.is_some()
     }
 }
@@ -67,7 +69,7 @@ mod tests {
     #[test]
     fn request() {
         let req1 = Request::new(From::from("subtract"), json!([42, 23]), Id::Number(1));
-        assert_eq!(req1.is_notification(), false);
+        assert!(!req1.is_notification());
         assert_eq!(
             req1,
             serde_json::from_str(r#"{
@@ -79,7 +81,7 @@ mod tests {
         );
 
         let req2 = Request::new_notification(From::from("subtract"), json!([42, 23]));
-        assert_eq!(req2.is_notification(), true);
+        assert!(req2.is_notification());
         assert_eq!(
             req2,
             serde_json::from_str(r#"{
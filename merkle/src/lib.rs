@@ -1,3 +1,5 @@
+#![allow(clippy::new_without_default, clippy::match_ref_pats, clippy::needless_borrowed_reference)]
+
 use std::cmp::Ordering;
 
 /// Inmutable binary tree data structure.
@@ -27,7 +29,7 @@ impl<T: Ord> Tree<T> {
                 match nval.cmp(val) {
                     Ordering::Less => l.insert(nval),
                     Ordering::Greater => r.insert(nval),
-                    _ => return
+                    _ => {}
                 }
             },
             &mut Tree::Empty => {
@@ -112,21 +114,12 @@ mod tests {
 
     #[test]
     fn empty() {
-        assert_eq!(
-            Tree::<i64>::new().is_empty(),
-            true
-        );
-        assert_eq!(
-            Tree::Empty::<i64>.is_empty(),
-            true
-        );
+        assert!(Tree::<i64>::new().is_empty());
+        assert!(Tree::Empty::<i64>.is_empty());
 
         let mut t1 = Tree::<i64>::new();
         t1.insert(5);
-        assert_eq!(
-            t1.is_empty(),
-            false
-        );
+        assert!(!t1.is_empty());
     }
 
     #[test]
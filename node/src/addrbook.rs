@@ -0,0 +1,423 @@
+// Persistent database of node addresses.
+//
+// Addresses we only heard about live in the "new" table; addresses we managed
+// to connect to are moved to the "tried" table. Both tables are split in
+// fixed-size buckets, and which bucket an address lands in depends on a
+// secret key and on the network group of the address (and, for new entries,
+// of the peer that told us about it). A single peer can thus only ever fill
+// a handful of buckets, no matter how many addresses it sends us.
+//
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use byteorder::{BigEndian, ByteOrder};
+use rand;
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use rmps::{Serializer as MPSerializer, Deserializer as MPDeserializer};
+use sha2::{Sha256, Digest};
+use protocol::{Node, NetAddr};
+
+/// Number of buckets in the new and tried tables
+pub const NEW_BUCKET_COUNT: usize = 256;
+pub const TRIED_BUCKET_COUNT: usize = 64;
+
+/// Addresses per bucket
+pub const BUCKET_SIZE: usize = 64;
+
+/// How many new buckets the addresses from a single source group can spread over
+const NEW_BUCKETS_PER_SOURCE: u64 = 8;
+
+/// How many tried buckets the addresses of a single group can spread over
+const TRIED_BUCKETS_PER_GROUP: u64 = 4;
+
+/// Delay before retrying an address after its first failure; doubles with
+/// every further consecutive failure, up to `RETRY_MAX` (seconds). Sessions
+/// ending within `RETRY_MAX` count as failures too.
+pub const RETRY_BASE: u64 = 5;
+pub const RETRY_MAX: u64 = 10 * 60;
+
+/// What we know about an address
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddrInfo {
+    pub node: Node,
+    /// Unix time the address was last announced or connected to
+    pub last_seen: u64,
+    /// Unix time of the last connection attempt, 0 if never tried
+    pub last_try: u64,
+    /// Total successful and failed connection attempts
+    pub successes: u32,
+    pub failures: u32,
+    /// Failed attempts and short sessions since the last session that
+    /// lasted, c.f. `AddrBook::disconnected`
+    pub attempts: u32,
+    /// Peer that announced the address; `None` if configured by hand
+    pub source: Option<IpAddr>,
+    /// Whether the address is in the tried table
+    pub tried: bool,
+}
+
+impl AddrInfo {
+    /// Unix time before which we should not dial the address again
+    pub fn retry_at(&self) -> u64 {
+        if self.attempts == 0 {
+            return 0;
+        }
+        let shift = ::std::cmp::min(self.attempts - 1, 16);
+        self.last_try + ::std::cmp::min(RETRY_BASE << shift, RETRY_MAX)
+    }
+}
+
+/// On-disk representation of the book
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    key: [u8; 32],
+    addrs: Vec<AddrInfo>,
+}
+
+pub struct AddrBook {
+    path: PathBuf,
+    /// Secret bucketing key, so that peers can't predict bucket placement
+    key: [u8; 32],
+    addrs: HashMap<SocketAddr, AddrInfo>,
+    new_buckets: Vec<Vec<SocketAddr>>,
+    tried_buckets: Vec<Vec<SocketAddr>>,
+}
+
+/// Network group of an address: /16 for IPv4, /32 for IPv6
+fn group(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        &IpAddr::V4(ref v4) => {
+            let o = v4.octets();
+            vec!(4, o[0], o[1])
+        },
+        &IpAddr::V6(ref v6) => {
+            if let Some(v4) = v6.to_ipv4() {
+                return group(&IpAddr::V4(v4));
+            }
+            let o = v6.octets();
+            vec!(6, o[0], o[1], o[2], o[3])
+        }
+    }
+}
+
+impl AddrBook {
+    /// Empty book, saved at `path`
+    pub fn new(path: &Path) -> AddrBook {
+        let mut key = [0; 32];
+        rand::thread_rng().fill(&mut key);
+        AddrBook {
+            path: path.to_path_buf(),
+            key: key,
+            addrs: HashMap::new(),
+            new_buckets: vec![Vec::new(); NEW_BUCKET_COUNT],
+            tried_buckets: vec![Vec::new(); TRIED_BUCKET_COUNT],
+        }
+    }
+
+    /// Load the book saved at `path`, or start an empty one
+    pub fn open(path: &Path) -> AddrBook {
+        match AddrBook::load(path) {
+            Ok(book) => book,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    println!("Could not load address book `{}`: {}", path.display(), e);
+                }
+                AddrBook::new(path)
+            }
+        }
+    }
+
+    fn load(path: &Path) -> io::Result<AddrBook> {
+        let f = File::open(path)?;
+        let mut de = MPDeserializer::new(f);
+        let stored: Stored = Deserialize::deserialize(&mut de)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut book = AddrBook::new(path);
+        book.key = stored.key;
+        for info in stored.addrs {
+            book.insert(info);
+        }
+        Ok(book)
+    }
+
+    /// Write the book to disk
+    pub fn save(&self) -> io::Result<()> {
+        let stored = Stored {
+            key: self.key,
+            addrs: self.addrs.values().cloned().collect(),
+        };
+        let mut buf = Vec::new();
+        stored.serialize(&mut MPSerializer::new(&mut buf))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        // Write to a temporary file first so a crash never leaves a truncated book
+        let tmp = self.path.with_extension("tmp");
+        File::create(&tmp)?.write_all(&buf)?;
+        ::std::fs::rename(&tmp, &self.path)
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> u64 {
+        let mut hasher = Sha256::default();
+        hasher.input(&self.key);
+        for part in parts {
+            hasher.input(part);
+        }
+        BigEndian::read_u64(&hasher.result()[..8])
+    }
+
+    fn new_bucket(&self, addr: &SocketAddr, source: &Option<IpAddr>) -> usize {
+        let addr_group = group(&addr.ip());
+        let source_group = match source {
+            &Some(ref ip) => group(ip),
+            &None => Vec::new()
+        };
+        let slot = self.hash(&[&addr_group, &source_group]) % NEW_BUCKETS_PER_SOURCE;
+        let mut slot_bytes = [0; 8];
+        BigEndian::write_u64(&mut slot_bytes, slot);
+        (self.hash(&[&source_group, &slot_bytes]) % NEW_BUCKET_COUNT as u64) as usize
+    }
+
+    fn tried_bucket(&self, addr: &SocketAddr) -> usize {
+        let addr_group = group(&addr.ip());
+        let addr_str = format!("{}", addr);
+        let slot = self.hash(&[addr_str.as_bytes()]) % TRIED_BUCKETS_PER_GROUP;
+        let mut slot_bytes = [0; 8];
+        BigEndian::write_u64(&mut slot_bytes, slot);
+        (self.hash(&[&addr_group, &slot_bytes]) % TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    /// Put an entry in its bucket, evicting the stalest one if the bucket is full
+    fn insert(&mut self, info: AddrInfo) {
+        let addr = info.node.socket_addr();
+        let bucket = if info.tried {
+            self.tried_bucket(&addr)
+        } else {
+            self.new_bucket(&addr, &info.source)
+        };
+
+        let full = if info.tried {
+            self.tried_buckets[bucket].len() >= BUCKET_SIZE
+        } else {
+            self.new_buckets[bucket].len() >= BUCKET_SIZE
+        };
+        if full {
+            let stalest = {
+                let b = if info.tried { &self.tried_buckets[bucket] } else { &self.new_buckets[bucket] };
+                *b.iter().min_by_key(|a| self.addrs[a].last_seen).unwrap()
+            };
+            let evicted = self.remove(&stalest).unwrap();
+
+            // Tried entries get a second chance in the new table
+            if evicted.tried {
+                let mut evicted = evicted;
+                evicted.tried = false;
+                self.insert(evicted);
+            }
+        }
+
+        if info.tried {
+            self.tried_buckets[bucket].push(addr);
+        } else {
+            self.new_buckets[bucket].push(addr);
+        }
+        self.addrs.insert(addr, info);
+    }
+
+    fn remove(&mut self, addr: &SocketAddr) -> Option<AddrInfo> {
+        let info = self.addrs.remove(addr)?;
+        for b in self.new_buckets.iter_mut().chain(self.tried_buckets.iter_mut()) {
+            b.retain(|a| a != addr);
+        }
+        Some(info)
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&AddrInfo> {
+        self.addrs.get(addr)
+    }
+
+    /// Record an announced address. `source` is the peer that told us about
+    /// it, `None` for addresses configured by hand.
+    pub fn add(&mut self, addr: &NetAddr, source: Option<IpAddr>, now: u64) {
+        // Don't let peers claim addresses were seen in the future
+        let seen = ::std::cmp::min(addr.last_seen, now);
+        let key = addr.node.socket_addr();
+        if let Some(info) = self.addrs.get_mut(&key) {
+            if seen > info.last_seen {
+                info.last_seen = seen;
+            }
+            return;
+        }
+
+        self.insert(AddrInfo {
+            node: addr.node.clone(),
+            last_seen: seen,
+            last_try: 0,
+            successes: 0,
+            failures: 0,
+            attempts: 0,
+            source: source,
+            tried: false,
+        });
+    }
+
+    /// Record a connection attempt
+    pub fn attempt(&mut self, addr: &SocketAddr, now: u64) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.last_try = now;
+        }
+    }
+
+    /// Record a failed connection attempt
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            info.failures += 1;
+            info.attempts += 1;
+        }
+    }
+
+    /// Record a successful connection, moving the address to the tried table.
+    /// The back-off is left as is until the session ends.
+    pub fn good(&mut self, addr: &SocketAddr, now: u64) {
+        let mut info = match self.remove(addr) {
+            Some(info) => info,
+            None => return
+        };
+        info.successes += 1;
+        info.last_seen = now;
+        info.tried = true;
+        self.insert(info);
+    }
+
+    /// Record the end of a session that got past the handshake and lasted
+    /// `duration` seconds. A session shorter than `RETRY_MAX` counts as a
+    /// failed attempt, so peers that keep dropping us back off too; a longer
+    /// one clears the back-off.
+    pub fn disconnected(&mut self, addr: &SocketAddr, duration: u64) {
+        if let Some(info) = self.addrs.get_mut(addr) {
+            if duration < RETRY_MAX {
+                info.attempts += 1;
+            } else {
+                info.attempts = 0;
+            }
+        }
+    }
+
+    /// Addresses that may be dialed at `now`, tried ones first, in random order
+    pub fn candidates(&self, now: u64) -> Vec<SocketAddr> {
+        let mut ready: Vec<&AddrInfo> = self.addrs.values()
+            .filter(|info| info.retry_at() <= now)
+            .collect();
+        rand::thread_rng().shuffle(&mut ready);
+        ready.sort_by_key(|info| !info.tried);
+        ready.iter().map(|info| info.node.socket_addr()).collect()
+    }
+
+    /// Random sample of at most `max` addresses, to answer `getaddr`
+    pub fn sample(&self, max: usize) -> Vec<NetAddr> {
+        let mut all: Vec<&AddrInfo> = self.addrs.values().collect();
+        rand::thread_rng().shuffle(&mut all);
+        all.iter().take(max).map(|info| NetAddr {
+            node: info.node.clone(),
+            last_seen: info.last_seen,
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::net::Ipv4Addr;
+
+    fn net_addr(a: u8, b: u8, c: u8, d: u8, port: u16) -> NetAddr {
+        NetAddr {
+            node: Node::from_socket_addr(&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), port)),
+            last_seen: 1000,
+        }
+    }
+
+    #[test]
+    fn flooding() {
+        let mut book = AddrBook::new(&env::temp_dir().join("addrbook-flooding.dat"));
+        let source = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+        // One source sending tons of addresses can only fill a few buckets
+        for i in 0..100u32 {
+            for j in 0..100u32 {
+                book.add(&net_addr(1 + i as u8, j as u8, 0, 1, 7878), source, 2000);
+            }
+        }
+        let max = NEW_BUCKETS_PER_SOURCE as usize * BUCKET_SIZE;
+        assert!(book.len() <= max);
+        assert!(book.len() > BUCKET_SIZE);
+
+        // Other sources still get room
+        let other = Some(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)));
+        book.add(&net_addr(8, 8, 8, 8, 7878), other, 2000);
+        assert!(book.get(&net_addr(8, 8, 8, 8, 7878).node.socket_addr()).is_some());
+    }
+
+    #[test]
+    fn tried() {
+        let mut book = AddrBook::new(&env::temp_dir().join("addrbook-tried.dat"));
+        let a = net_addr(1, 2, 3, 4, 7878);
+        let addr = a.node.socket_addr();
+        book.add(&a, None, 2000);
+        assert!(!book.get(&addr).unwrap().tried);
+        assert_eq!(book.get(&addr).unwrap().last_seen, 1000);
+
+        // Failures back off
+        book.attempt(&addr, 2000);
+        book.failed(&addr);
+        book.attempt(&addr, 2010);
+        book.failed(&addr);
+        assert_eq!(book.get(&addr).unwrap().retry_at(), 2010 + 2 * RETRY_BASE);
+        assert!(book.candidates(2010).is_empty());
+        assert_eq!(book.candidates(2010 + 2 * RETRY_BASE), vec!(addr));
+
+        // Success moves the address to the tried table
+        book.attempt(&addr, 3000);
+        book.good(&addr, 3000);
+        let info = book.get(&addr).unwrap();
+        assert!(info.tried);
+        assert_eq!((info.successes, info.failures, info.attempts), (1, 2, 2));
+
+        // Sessions dropped early back off like failures, lasting ones reset
+        book.disconnected(&addr, 1);
+        assert_eq!(book.get(&addr).unwrap().retry_at(), 3000 + 4 * RETRY_BASE);
+        book.disconnected(&addr, RETRY_MAX);
+        assert_eq!(book.get(&addr).unwrap().retry_at(), 0);
+    }
+
+    #[test]
+    fn persistence() {
+        let path = env::temp_dir().join("addrbook-persistence.dat");
+        let mut book = AddrBook::new(&path);
+        let source = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        book.add(&net_addr(1, 2, 3, 4, 7878), source, 2000);
+        book.add(&net_addr(5, 6, 7, 8, 7879), None, 2000);
+        book.good(&net_addr(5, 6, 7, 8, 7879).node.socket_addr(), 3000);
+        book.save().unwrap();
+
+        let loaded = AddrBook::open(&path);
+        assert_eq!(loaded.key, book.key);
+        assert_eq!(loaded.len(), 2);
+        let a = loaded.get(&net_addr(1, 2, 3, 4, 7878).node.socket_addr()).unwrap();
+        assert_eq!(a.source, source);
+        assert!(!a.tried);
+        let b = loaded.get(&net_addr(5, 6, 7, 8, 7879).node.socket_addr()).unwrap();
+        assert!(b.tried);
+        assert_eq!(b.last_seen, 3000);
+
+        let _ = ::std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,194 @@
+// Block assembly.
+//
+// A candidate block confirms the mempool transactions paying the most per
+// byte, up to the block size limit. A transaction can only be mined along
+// with the unconfirmed ones it spends from (its ancestors), so transactions
+// are picked by the fee rate of that whole package: a well-paying child can
+// pull in a cheap parent. Each package goes in parents first, and the
+// coinbase, paying the subsidy plus every fee, goes in front.
+//
+// Packages wait in a priority queue. Once one is picked, only the packages of
+// its descendants change, as their ancestors got in; those are updated and
+// queued again, and stale queue entries are skipped when they come up.
+//
+use std::cmp;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use rmps;
+use chain::Chain;
+use mempool;
+use mempool::{FeeRate, Mempool};
+use protocol::{Address, Block, BlockHash, Transaction, TxHash};
+use retarget;
+use validation;
+
+/// A block ready to be mined
+pub struct Template {
+    pub block: Block,
+    pub height: u64,
+    /// Fees of the block's transactions, paid to the miner on top of the subsidy
+    pub fees: u64,
+}
+
+/// Pick mempool transactions worth at most `max_size` encoded bytes, by
+/// ancestor-package fee rate; returns them in an order they can be mined in,
+/// along with their fees
+pub fn select(mempool: &Mempool, max_size: usize) -> (Vec<Transaction>, u64) {
+    // Fees and size of every transaction along with its ancestors not
+    // selected yet, and the queue of packages by fee rate. Ties go to the
+    // transaction paying more on its own.
+    let order: Vec<TxHash> = mempool.by_fee_rate().iter().map(|entry| entry.tx.hash()).collect();
+    let mut packages: HashMap<TxHash, (u64, usize)> = HashMap::new();
+    let mut queue: BinaryHeap<(FeeRate, Reverse<usize>)> = BinaryHeap::new();
+    for (i, hash) in order.iter().enumerate() {
+        let package = mempool.ancestors(hash).iter().chain(Some(hash))
+            .map(|h| mempool.entry(h).unwrap())
+            .fold((0u64, 0usize), |(fee, bytes), e| (fee.saturating_add(e.fee), bytes + e.size));
+        packages.insert(*hash, package);
+        queue.push((mempool::fee_rate(package.0, package.1), Reverse(i)));
+    }
+    let position: HashMap<TxHash, usize> = order.iter().enumerate().map(|(i, h)| (*h, i)).collect();
+
+    let mut selected: HashSet<TxHash> = HashSet::new();
+    let mut skipped: HashSet<TxHash> = HashSet::new();
+    let mut txs = Vec::new();
+    let mut fees = 0u64;
+    let mut size = 0usize;
+
+    while let Some((rate, Reverse(i))) = queue.pop() {
+        let hash = order[i];
+        let (fee, bytes) = packages[&hash];
+        if selected.contains(&hash) || skipped.contains(&hash) || rate != mempool::fee_rate(fee, bytes) {
+            continue;
+        }
+        let mut package: Vec<TxHash> = mempool.ancestors(&hash).into_iter()
+            .filter(|h| !selected.contains(h))
+            .collect();
+        if package.iter().any(|h| skipped.contains(h)) {
+            continue;
+        }
+        package.push(hash);
+        if size + bytes > max_size {
+            // Its ancestors may still fit on their own
+            skipped.insert(hash);
+            continue;
+        }
+        size += bytes;
+        fees = fees.saturating_add(fee);
+
+        // Descendants no longer pay for what just got in
+        for h in package {
+            let entry = mempool.entry(&h).unwrap();
+            for descendant in mempool.descendants(&h) {
+                if descendant == h || selected.contains(&descendant) {
+                    continue;
+                }
+                let package = packages.get_mut(&descendant).unwrap();
+                *package = (package.0.saturating_sub(entry.fee), package.1 - entry.size);
+                queue.push((mempool::fee_rate(package.0, package.1), Reverse(position[&descendant])));
+            }
+            txs.push(entry.tx.clone());
+            selected.insert(h);
+        }
+    }
+    (txs, fees)
+}
+
+/// Block on top of the chain's tip paying to `miner`, `now` being the
+/// current Unix time
+pub fn assemble(chain: &Chain, mempool: &Mempool, miner: Address, now: u64) -> Template {
+    let params = chain.params();
+    let tip = chain.tip();
+    let history = chain.history(&tip);
+    let height = chain.height() + 1;
+
+    // Room left next to the header and the largest possible coinbase; the
+    // array of transactions may take a few more bytes to encode when longer
+    let coinbase = Transaction::coinbase(height, u64::max_value(), miner);
+    let empty = Block::new(BlockHash::new(), 0, 0, vec![coinbase]);
+    let overhead = rmps::to_vec(&empty).map(|buf| buf.len()).unwrap_or(validation::MAX_BLOCK_SIZE) + 4;
+    let (mut txs, fees) = select(mempool, validation::MAX_BLOCK_SIZE.saturating_sub(overhead));
+
+    let reward = params.block_reward(height).saturating_add(fees);
+    txs.insert(0, Transaction::coinbase(height, reward, miner));
+    let bits = retarget::next_bits(&params.retarget, height, &history);
+    let timestamp = cmp::max(now, validation::median_time_past(&history) + 1);
+    Template {
+        block: Block::new(tip, timestamp, bits, txs),
+        height: height,
+        fees: fees,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chainparams::ChainParams;
+    use mempool::{encoded_size, DEFAULT_EXPIRY, DEFAULT_MAX_SIZE};
+    use test_util::{funded, owner, spend};
+
+    #[test]
+    fn packages() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[1000, 1000]);
+
+        // A well-paying child pulls its cheap parent in
+        let parent = spend(1, &[(funding, 0, 1000)], &[999]);
+        let child = spend(1, &[(parent.hash(), 0, 999)], &[499]);
+        let rich = spend(1, &[(funding, 1, 1000)], &[700]);
+        let mut pool = Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, 0);
+        for tx in &[&parent, &child, &rich] {
+            pool.add((*tx).clone(), &set, 2, &params, 0).unwrap();
+        }
+        let (txs, fees) = select(&pool, DEFAULT_MAX_SIZE);
+        let order: Vec<TxHash> = txs.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![rich.hash(), parent.hash(), child.hash()]);
+        assert_eq!(fees, 801);
+
+        // Packages that don't fit are left out, their ancestors may not be
+        let (txs, fees) = select(&pool, encoded_size(&rich) + encoded_size(&parent));
+        assert_eq!(txs.len(), 2);
+        assert_eq!(fees, 301);
+        assert!(select(&pool, 0).0.is_empty());
+    }
+
+    #[test]
+    fn updates() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[1000, 1000, 1000]);
+
+        // Once its ancestors are in, a grandchild stands on its own fee
+        let parent = spend(1, &[(funding, 0, 1000)], &[999]);
+        let child = spend(1, &[(parent.hash(), 0, 999)], &[499]);
+        let grandchild = spend(1, &[(child.hash(), 0, 499)], &[489]);
+        let rich = spend(1, &[(funding, 1, 1000)], &[700]);
+        let low = spend(1, &[(funding, 2, 1000)], &[950]);
+        let mut pool = Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, 0);
+        for tx in &[&parent, &child, &grandchild, &rich, &low] {
+            pool.add((*tx).clone(), &set, 2, &params, 0).unwrap();
+        }
+        let (txs, fees) = select(&pool, DEFAULT_MAX_SIZE);
+        let order: Vec<TxHash> = txs.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![rich.hash(), parent.hash(), child.hash(), low.hash(), grandchild.hash()]);
+        assert_eq!(fees, 861);
+    }
+
+    #[test]
+    fn template() {
+        let params = ChainParams::regtest();
+        let chain = Chain::new(params.clone());
+        let pool = Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, 0);
+        let miner = owner(1);
+        let template = assemble(&chain, &pool, miner, 1);
+
+        let block = &template.block;
+        assert_eq!((template.height, template.fees), (1, 0));
+        assert_eq!(block.header.prev_hash, params.genesis_hash());
+        assert_eq!(block.header.merkle_root, block.compute_merkle_root());
+        assert!(block.header.timestamp > params.genesis.header.timestamp);
+        assert_eq!(block.txs.len(), 1);
+        assert_eq!(block.txs[0].coinbase_data().unwrap().height, 1);
+        assert_eq!(block.txs[0].outputs()[0].amount, params.block_reward(1));
+        assert_eq!(block.txs[0].outputs()[0].creditor, miner);
+    }
+}
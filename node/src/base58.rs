@@ -1,13 +1,105 @@
 // Wrapping base conversion in a more convenient way
 //
+// `encode_check` adds what users need to type addresses safely: a version
+// byte telling networks apart, and a checksum catching typos.
+//
 extern crate rust_base58;
 
+use std::error::Error;
+use std::fmt;
+use sha2::{Sha256, Digest};
 use self::rust_base58::{ToBase58, FromBase58};
+use self::rust_base58::base58::FromBase58Error;
+
+/// Length of the checksum appended by `encode_check`
+pub const CHECKSUM_LENGTH: usize = 4;
 
 pub fn encode<T: ?Sized + ToBase58 + AsRef<[u8]>>(input: &T) -> String {
     input.to_base58()
 }
 
-pub fn decode<T :?Sized + FromBase58 + AsRef<[u8]>>(input: &T) -> Result<Vec<u8>, self::rust_base58::base58::FromBase58Error> {
+pub fn decode<T :?Sized + FromBase58 + AsRef<[u8]>>(input: &T) -> Result<Vec<u8>, FromBase58Error> {
     input.from_base58()
 }
+
+/// First bytes of the double SHA-256 of `data`
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+    let first = Sha256::digest(data);
+    let second = Sha256::digest(&first);
+    let mut sum = [0; CHECKSUM_LENGTH];
+    sum.copy_from_slice(&second[..CHECKSUM_LENGTH]);
+    sum
+}
+
+/// Encode `version || payload || checksum`
+pub fn encode_check(version: u8, payload: &[u8]) -> String {
+    let mut data = Vec::with_capacity(1 + payload.len() + CHECKSUM_LENGTH);
+    data.push(version);
+    data.extend_from_slice(payload);
+    let sum = checksum(&data);
+    data.extend_from_slice(&sum);
+    encode(&data[..])
+}
+
+/// Failure to decode a checksummed base58 string
+#[derive(Debug, PartialEq)]
+pub enum CheckError {
+    /// Not a base58 string
+    Base58(FromBase58Error),
+    /// Too short to hold a version byte and a checksum
+    TooShort,
+    BadChecksum,
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CheckError::Base58(FromBase58Error::InvalidBase58Byte(b, i)) =>
+                write!(f, "invalid base58 character {:?} at position {}", b as char, i),
+            &CheckError::TooShort => write!(f, "too short"),
+            &CheckError::BadChecksum => write!(f, "bad checksum"),
+        }
+    }
+}
+
+impl Error for CheckError {
+    fn description(&self) -> &str {
+        "invalid checksummed base58 string"
+    }
+}
+
+/// Decode a string made by `encode_check`; returns its version and payload
+pub fn decode_check(input: &str) -> Result<(u8, Vec<u8>), CheckError> {
+    let data = decode(input).map_err(CheckError::Base58)?;
+    if data.len() < 1 + CHECKSUM_LENGTH {
+        return Err(CheckError::TooShort);
+    }
+
+    let (body, sum) = data.split_at(data.len() - CHECKSUM_LENGTH);
+    if checksum(body) != sum {
+        return Err(CheckError::BadChecksum);
+    }
+    Ok((body[0], body[1..].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let encoded = encode_check(0x19, b"payload");
+        assert_eq!(decode_check(&encoded), Ok((0x19, b"payload".to_vec())));
+        assert_eq!(decode_check(&encode_check(0, b"")), Ok((0, Vec::new())));
+
+        // A typo anywhere is caught
+        for i in 0..encoded.len() {
+            let mut typo = encoded.clone().into_bytes();
+            typo[i] = if typo[i] == b'2' { b'3' } else { b'2' };
+            assert!(decode_check(&String::from_utf8(typo).unwrap()).is_err(), "typo at {}", i);
+        }
+
+        assert_eq!(decode_check("1111"), Err(CheckError::TooShort));
+        assert_eq!(decode_check("0OIl"), Err(CheckError::Base58(FromBase58Error::InvalidBase58Byte(b'0', 0))));
+    }
+}
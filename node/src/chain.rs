@@ -0,0 +1,633 @@
+// The block tree.
+//
+// Every header we accept goes into the block index, along with its height,
+// the work of the chain it ends and how far it got through validation. The
+// active chain ends at the block with the most cumulative work among those we
+// have, along with all their ancestors. On a tie the current tip stays, and
+// otherwise the block whose header came first wins.
+//
+// When a heavier branch shows up, the active chain is disconnected back to
+// the fork point using the undo data saved when connecting it, and the branch
+// is connected in its place. A block of the branch that turns out invalid is
+// marked so along with its descendants, and the best remaining chain wins,
+// possibly the one we started from. That is only done for blocks whose data
+// matches their header, signatures included: a copy corrupted in transit
+// fails the context-free checks and is dropped, so the genuine block can
+// still come.
+//
+// Headers may run ahead of blocks: the best header is the one ending the
+// chain with the most work among all headers, and block download follows it.
+//
+// Both the headers and the blocks that could end the active chain are kept
+// ordered by work, so neither choice scans the index.
+//
+use std::cmp;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use chainparams::ChainParams;
+use pow;
+use pow::U256;
+use protocol::{Block, BlockHash, BlockHeader, Transaction};
+use utxo::{BlockUndo, UtxoSet};
+use validation;
+use validation::BlockError;
+
+/// How far a block got through validation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockStatus {
+    /// Header is valid, block not received yet
+    Header,
+    /// Block passed context-free checks, but was never connected
+    Data,
+    /// Block was connected to the UTXO set
+    Valid,
+    /// Block or one of its ancestors is invalid
+    Invalid,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockIndexEntry {
+    pub header: BlockHeader,
+    pub height: u64,
+    /// Work of the chain ending at this block, c.f. `pow::block_work`
+    pub chain_work: U256,
+    pub status: BlockStatus,
+    /// Order headers were received in, to break ties in work
+    sequence: u64,
+}
+
+/// Orders blocks by chain work, then by how early their header came
+type WorkKey = (U256, Reverse<u64>);
+
+impl BlockIndexEntry {
+    fn key(&self) -> WorkKey {
+        (self.chain_work, Reverse(self.sequence))
+    }
+}
+
+/// What activating the best chain changed
+#[derive(Debug, Default)]
+pub struct ChainUpdate {
+    /// Blocks that left the active chain, tip first
+    pub disconnected: Vec<BlockHash>,
+    /// Blocks that joined the active chain, in order
+    pub connected: Vec<BlockHash>,
+    /// Transactions of the disconnected blocks that are not confirmed any
+    /// more, in block order; coinbases are left out
+    pub unconfirmed: Vec<Transaction>,
+    /// Blocks found invalid while connecting them
+    pub invalid: Vec<(BlockHash, BlockError)>,
+}
+
+pub struct Chain {
+    params: ChainParams,
+    index: HashMap<BlockHash, BlockIndexEntry>,
+    blocks: HashMap<BlockHash, Block>,
+    /// Undo data of the active chain's blocks
+    undo: HashMap<BlockHash, BlockUndo>,
+    /// Active chain, by height
+    active: Vec<BlockHash>,
+    /// Blocks building on each block in the index
+    children: HashMap<BlockHash, Vec<BlockHash>>,
+    /// Headers not known to be invalid, the best one last
+    headers: BTreeMap<WorkKey, BlockHash>,
+    /// Blocks not known to be invalid that have data along with all their
+    /// ancestors, i.e. those the active chain could end at, the best one last
+    candidates: BTreeMap<WorkKey, BlockHash>,
+    /// Blocks with data waiting on an ancestor's, by parent
+    unlinked: HashMap<BlockHash, Vec<BlockHash>>,
+    utxos: UtxoSet,
+    next_sequence: u64,
+}
+
+impl Chain {
+    /// Chain holding only the network's genesis block
+    pub fn new(params: ChainParams) -> Chain {
+        let genesis = params.genesis.clone();
+        let hash = genesis.hash();
+        let entry = BlockIndexEntry {
+            header: genesis.header.clone(),
+            height: 0,
+            chain_work: pow::block_work(genesis.header.bits),
+            status: BlockStatus::Valid,
+            sequence: 0,
+        };
+        let mut headers = BTreeMap::new();
+        headers.insert(entry.key(), hash);
+        let mut index = HashMap::new();
+        index.insert(hash, entry);
+        let mut blocks = HashMap::new();
+        blocks.insert(hash, genesis);
+
+        Chain {
+            params: params,
+            index: index,
+            blocks: blocks,
+            undo: HashMap::new(),
+            active: vec![hash],
+            children: HashMap::new(),
+            candidates: headers.clone(),
+            headers: headers,
+            unlinked: HashMap::new(),
+            utxos: UtxoSet::new(),
+            next_sequence: 1,
+        }
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Last block of the active chain
+    pub fn tip(&self) -> BlockHash {
+        *self.active.last().unwrap()
+    }
+
+    /// Height of the tip; the genesis block is at 0
+    pub fn height(&self) -> u64 {
+        self.active.len() as u64 - 1
+    }
+
+    /// End of the chain of headers with the most work
+    pub fn best_header(&self) -> BlockHash {
+        *self.headers.values().next_back().unwrap()
+    }
+
+    /// UTXO set as of the tip
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.utxos
+    }
+
+    pub fn get(&self, hash: &BlockHash) -> Option<&BlockIndexEntry> {
+        self.index.get(hash)
+    }
+
+    pub fn block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.blocks.get(hash)
+    }
+
+    pub fn has_block(&self, hash: &BlockHash) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    /// Whether the block is part of the active chain
+    pub fn is_active(&self, hash: &BlockHash) -> bool {
+        match self.index.get(hash) {
+            Some(entry) => self.active.get(entry.height as usize) == Some(hash),
+            None => false
+        }
+    }
+
+    /// Headers of the blocks up to and including `hash`, oldest first, as many
+    /// as validating its child takes
+    pub fn history(&self, hash: &BlockHash) -> Vec<BlockHeader> {
+        let len = cmp::max(self.params.retarget.history_len(), validation::MEDIAN_TIME_SPAN);
+        let mut history = Vec::with_capacity(len);
+        let mut next = self.index.get(hash);
+        while let Some(entry) = next {
+            if history.len() == len {
+                break;
+            }
+            history.push(entry.header.clone());
+            next = if entry.height > 0 { self.index.get(&entry.header.prev_hash) } else { None };
+        }
+        history.reverse();
+        history
+    }
+
+    /// Check a header and add it to the index; `now` is the current Unix time
+    pub fn accept_header(&mut self, header: &BlockHeader, now: u64) -> Result<BlockHash, BlockError> {
+        let hash = header.hash();
+        if let Some(entry) = self.index.get(&hash) {
+            if entry.status == BlockStatus::Invalid {
+                return Err(BlockError::InvalidAncestor(hash));
+            }
+            return Ok(hash);
+        }
+
+        let (height, parent_work) = match self.index.get(&header.prev_hash) {
+            Some(parent) if parent.status == BlockStatus::Invalid =>
+                return Err(BlockError::InvalidAncestor(header.prev_hash)),
+            Some(parent) => (parent.height + 1, parent.chain_work),
+            None => return Err(BlockError::UnknownParent(header.prev_hash))
+        };
+        validation::check_header(header, now)?;
+        validation::check_header_context(header, &self.history(&header.prev_hash), height, &self.params)?;
+
+        let work = parent_work.checked_add(&pow::block_work(header.bits)).unwrap_or(parent_work);
+        let entry = BlockIndexEntry {
+            header: header.clone(),
+            height: height,
+            chain_work: work,
+            status: BlockStatus::Header,
+            sequence: self.next_sequence,
+        };
+        self.next_sequence += 1;
+        self.headers.insert(entry.key(), hash);
+        self.index.insert(hash, entry);
+        self.children.entry(header.prev_hash).or_default().push(hash);
+        Ok(hash)
+    }
+
+    /// Hashes of the chain ending at `hash`, to find where another node's
+    /// chain forks off ours: the last ten blocks, then further and further
+    /// apart, and the genesis block last
+    pub fn locator(&self, hash: &BlockHash) -> Vec<BlockHash> {
+        let mut locator = Vec::new();
+        let mut hash = *hash;
+        let mut step = 1;
+        loop {
+            locator.push(hash);
+            let height = self.index[&hash].height;
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            hash = self.ancestor(&hash, height.saturating_sub(step)).unwrap();
+        }
+        locator
+    }
+
+    /// Headers of the active chain following the first locator hash in it,
+    /// up to `stop` and at most `max` of them
+    pub fn headers_after(&self, locator: &[BlockHash], stop: &BlockHash, max: usize) -> Vec<BlockHeader> {
+        let fork = locator.iter()
+            .find(|hash| self.is_active(hash))
+            .map_or(0, |hash| self.index[hash].height);
+        let mut headers = Vec::new();
+        for hash in self.active.iter().skip(fork as usize + 1).take(max) {
+            headers.push(self.index[hash].header.clone());
+            if hash == stop {
+                break;
+            }
+        }
+        headers
+    }
+
+    /// Blocks of the best header chain we don't have, at most `window` above
+    /// the tip, lowest first
+    pub fn missing_blocks(&self, window: u64) -> Vec<BlockHash> {
+        let best_header = self.best_header();
+        let last = cmp::min(self.index[&best_header].height, self.height() + window);
+        let mut missing = Vec::new();
+        let mut next = self.ancestor(&best_header, last);
+        while let Some(hash) = next {
+            if self.is_active(&hash) {
+                break;
+            }
+            if !self.blocks.contains_key(&hash) {
+                missing.push(hash);
+            }
+            next = Some(self.index[&hash].header.prev_hash);
+        }
+        missing.reverse();
+        missing
+    }
+
+    /// Check a block and store it, then switch to the best chain
+    pub fn accept_block(&mut self, block: Block, now: u64) -> Result<ChainUpdate, BlockError> {
+        let hash = block.hash();
+        if self.blocks.contains_key(&hash) {
+            return Ok(ChainUpdate::default());
+        }
+        validation::check_block(&block, now)?;
+        self.accept_header(&block.header, now)?;
+
+        let prev = block.header.prev_hash;
+        self.blocks.insert(hash, block);
+        self.index.get_mut(&hash).unwrap().status = BlockStatus::Data;
+        let linked = self.candidates.contains_key(&self.index[&prev].key());
+        if linked {
+            self.link(hash);
+        } else {
+            self.unlinked.entry(prev).or_default().push(hash);
+        }
+        Ok(self.activate_best_chain())
+    }
+
+    /// A block and all its ancestors have data: it may end the active chain,
+    /// and so may the blocks that were waiting on it
+    fn link(&mut self, hash: BlockHash) {
+        let mut queue = vec![hash];
+        while let Some(hash) = queue.pop() {
+            self.candidates.insert(self.index[&hash].key(), hash);
+            queue.extend(self.unlinked.remove(&hash).unwrap_or_default());
+        }
+    }
+
+    /// Connect and disconnect blocks until the active chain is the best one
+    fn activate_best_chain(&mut self) -> ChainUpdate {
+        let mut update = ChainUpdate::default();
+        let old_tip = self.tip();
+        loop {
+            let best = self.best_candidate();
+            if best == self.tip() {
+                break;
+            }
+
+            let branch = self.branch(&best);
+            let fork = self.index[&branch[0]].height - 1;
+            while self.height() > fork {
+                self.disconnect_tip();
+            }
+            for hash in branch {
+                match self.connect(&hash) {
+                    Ok(()) => (),
+                    Err(e) => {
+                        self.mark_invalid(&hash);
+                        update.invalid.push((hash, e));
+                        break;
+                    }
+                }
+            }
+        }
+
+        // A failed reorg may have brought blocks back; only report net changes
+        let mut hash = old_tip;
+        while !self.is_active(&hash) {
+            update.disconnected.push(hash);
+            hash = self.index[&hash].header.prev_hash;
+        }
+        let fork = self.index[&hash].height as usize;
+        update.connected = self.active[fork + 1..].to_vec();
+
+        let confirmed: HashSet<_> = update.connected.iter()
+            .flat_map(|hash| self.blocks[hash].txs.iter().map(|tx| tx.hash()))
+            .collect();
+        update.unconfirmed = update.disconnected.iter().rev()
+            .flat_map(|hash| self.blocks[hash].txs.iter())
+            .filter(|tx| !tx.is_coinbase() && !confirmed.contains(&tx.hash()))
+            .cloned().collect();
+        update
+    }
+
+    /// Block with the most work among those that have every ancestor's data
+    /// and no invalid one; the tip unless another has strictly more work
+    fn best_candidate(&self) -> BlockHash {
+        let tip = self.tip();
+        match self.candidates.iter().next_back() {
+            Some((&(work, _), hash)) if work > self.index[&tip].chain_work => *hash,
+            _ => tip
+        }
+    }
+
+    /// Blocks from the fork point with the active chain (excluded) to `hash`
+    fn branch(&self, hash: &BlockHash) -> Vec<BlockHash> {
+        let mut branch = Vec::new();
+        let mut hash = *hash;
+        while !self.is_active(&hash) {
+            branch.push(hash);
+            hash = self.index[&hash].header.prev_hash;
+        }
+        branch.reverse();
+        branch
+    }
+
+    /// Validate a block whose parent is the tip and make it the tip
+    fn connect(&mut self, hash: &BlockHash) -> Result<(), BlockError> {
+        let height = self.height() + 1;
+        let block = &self.blocks[hash];
+        let history = self.history(&block.header.prev_hash);
+        validation::check_block_context(block, &history, height, &self.utxos, &self.params)?;
+        let undo = self.utxos.connect_block(block, height).map_err(BlockError::Connect)?;
+
+        self.undo.insert(*hash, undo);
+        self.active.push(*hash);
+        self.index.get_mut(hash).unwrap().status = BlockStatus::Valid;
+        Ok(())
+    }
+
+    fn disconnect_tip(&mut self) -> BlockHash {
+        let hash = self.active.pop().unwrap();
+        let undo = self.undo.remove(&hash).unwrap();
+        self.utxos.disconnect_block(&self.blocks[&hash], &undo)
+            .expect("undo data of an active block matches it");
+        hash
+    }
+
+    /// Mark a block and all its descendants invalid; only for failures of data
+    /// the header commits to, c.f. `Block::compute_merkle_root`
+    fn mark_invalid(&mut self, hash: &BlockHash) {
+        let mut queue = vec![*hash];
+        while let Some(hash) = queue.pop() {
+            let key = {
+                let entry = self.index.get_mut(&hash).unwrap();
+                entry.status = BlockStatus::Invalid;
+                entry.key()
+            };
+            self.headers.remove(&key);
+            self.candidates.remove(&key);
+            self.unlinked.remove(&hash);
+            queue.extend(self.children.get(&hash).into_iter().flat_map(|children| children.iter().cloned()));
+        }
+    }
+
+    /// Ancestor of a block at the given height
+    fn ancestor(&self, hash: &BlockHash, height: u64) -> Option<BlockHash> {
+        let mut hash = *hash;
+        loop {
+            let entry = self.index.get(&hash)?;
+            if entry.height == height {
+                return Some(hash);
+            }
+            if entry.height < height {
+                return None;
+            }
+            hash = entry.header.prev_hash;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use miner;
+    use protocol;
+    use protocol::{Address, SigHashType};
+    use test_util::{keypair, owner, spend};
+
+    fn chain() -> Chain {
+        let mut params = ChainParams::regtest();
+        params.coinbase_maturity = 1;
+        Chain::new(params)
+    }
+
+    fn solve(mut block: Block) -> Block {
+        block.header.nonce = miner::search(&block.header, 0, 1, &AtomicBool::new(false)).unwrap();
+        block
+    }
+
+    /// Solved block on top of `prev`, its coinbase paying `reward` to our key
+    fn child(chain: &Chain, prev: &BlockHash, reward: u64, txs: Vec<Transaction>) -> Block {
+        let parent = chain.get(prev).unwrap();
+        let height = parent.height + 1;
+        let coinbase = Transaction::coinbase(height, reward, owner(1));
+        let mut all = vec![coinbase];
+        all.extend(txs);
+        solve(Block::new(*prev, parent.header.timestamp + 1, pow::REGTEST_BITS, all))
+    }
+
+    fn add(chain: &mut Chain, block: &Block) -> ChainUpdate {
+        chain.accept_block(block.clone(), protocol::unix_time()).unwrap()
+    }
+
+    #[test]
+    fn reorg() {
+        let mut chain = chain();
+        let genesis = chain.tip();
+        let reward = chain.params().block_reward(1);
+
+        let a1 = child(&chain, &genesis, reward, vec![]);
+        add(&mut chain, &a1);
+        let tx = spend(1, &[(a1.txs[0].hash(), 0, reward)], &[reward]);
+        let a2 = child(&chain, &a1.hash(), reward, vec![tx.clone()]);
+        let update = add(&mut chain, &a2);
+        assert_eq!(update.connected, vec![a2.hash()]);
+        assert_eq!((chain.tip(), chain.height()), (a2.hash(), 2));
+
+        // Same work as the active chain: first seen wins
+        let b2 = child(&chain, &a1.hash(), reward, vec![]);
+        let update = add(&mut chain, &b2);
+        assert!(update.connected.is_empty());
+        assert_eq!(chain.tip(), a2.hash());
+        assert_eq!(chain.get(&b2.hash()).unwrap().status, BlockStatus::Data);
+
+        // More work: switch branches, and the spend is unconfirmed again
+        let b3 = child(&chain, &b2.hash(), reward, vec![]);
+        let update = add(&mut chain, &b3);
+        assert_eq!(update.disconnected, vec![a2.hash()]);
+        assert_eq!(update.connected, vec![b2.hash(), b3.hash()]);
+        assert_eq!(update.unconfirmed.iter().map(|tx| tx.hash()).collect::<Vec<_>>(), vec![tx.hash()]);
+        assert_eq!((chain.tip(), chain.height()), (b3.hash(), 3));
+        assert!(!chain.is_active(&a2.hash()));
+        assert_eq!(chain.utxos().len(), 3);
+
+        // Unknown parents are not our peer's fault
+        let coinbase = Transaction::coinbase(4, reward, Address::new());
+        let orphan = solve(Block::new(BlockHash::new(), protocol::unix_time(), pow::REGTEST_BITS, vec![coinbase]));
+        assert_eq!(chain.accept_block(orphan, protocol::unix_time()).err(), Some(BlockError::UnknownParent(BlockHash::new())));
+    }
+
+    #[test]
+    fn invalid_branch() {
+        let mut chain = chain();
+        let genesis = chain.tip();
+        let reward = chain.params().block_reward(1);
+
+        let a1 = child(&chain, &genesis, reward, vec![]);
+        add(&mut chain, &a1);
+        let a2 = child(&chain, &a1.hash(), reward, vec![]);
+        add(&mut chain, &a2);
+        let b2 = child(&chain, &a1.hash(), reward, vec![]);
+        add(&mut chain, &b2);
+
+        // The header is fine, but the coinbase overpays: back to where we were
+        let b3 = child(&chain, &b2.hash(), reward + 1, vec![]);
+        let update = add(&mut chain, &b3);
+        assert_eq!(update.invalid, vec![(b3.hash(), BlockError::CoinbaseOverpays { allowed: reward, actual: reward + 1 })]);
+        assert!(update.connected.is_empty() && update.disconnected.is_empty());
+        assert_eq!(chain.tip(), a2.hash());
+        assert_eq!(chain.get(&b3.hash()).unwrap().status, BlockStatus::Invalid);
+
+        // Nothing builds on an invalid block
+        let b4 = child(&chain, &b3.hash(), reward, vec![]);
+        assert_eq!(chain.accept_block(b4, protocol::unix_time()).err(), Some(BlockError::InvalidAncestor(b3.hash())));
+        assert_eq!(chain.history(&a2.hash()).last(), Some(&a2.header));
+    }
+
+    #[test]
+    fn equal_work() {
+        let mut chain = chain();
+        let genesis = chain.tip();
+        let reward = chain.params().block_reward(1);
+
+        // Headers of a branch come first, but its block comes last: having
+        // the same work, it doesn't take us off our tip
+        let a1 = child(&chain, &genesis, reward, vec![]);
+        let b1 = child(&chain, &genesis, reward - 1, vec![]);
+        chain.accept_header(&b1.header, protocol::unix_time()).unwrap();
+        add(&mut chain, &a1);
+        assert_eq!(chain.tip(), a1.hash());
+        let update = add(&mut chain, &b1);
+        assert!(update.connected.is_empty());
+        assert_eq!(chain.tip(), a1.hash());
+        assert_eq!(chain.best_header(), b1.hash());
+
+        // Blocks whose parent came later join the candidates along with it
+        let b2 = child(&chain, &b1.hash(), reward, vec![]);
+        chain.accept_header(&b2.header, protocol::unix_time()).unwrap();
+        let b3 = child(&chain, &b2.hash(), reward, vec![]);
+        add(&mut chain, &b3);
+        assert_eq!(chain.tip(), a1.hash());
+        let update = add(&mut chain, &b2);
+        assert_eq!(update.connected, vec![b1.hash(), b2.hash(), b3.hash()]);
+        assert_eq!(chain.best_header(), b3.hash());
+    }
+
+    #[test]
+    fn tampered_block() {
+        let mut chain = chain();
+        let genesis = chain.tip();
+        let reward = chain.params().block_reward(1);
+
+        let a1 = child(&chain, &genesis, reward, vec![]);
+        add(&mut chain, &a1);
+        let tx = spend(1, &[(a1.txs[0].hash(), 0, reward)], &[reward]);
+        let a2 = child(&chain, &a1.hash(), reward, vec![tx]);
+
+        // A relayed copy with a bad signature has the same hash, but doesn't
+        // match its header and leaves no trace
+        let mut tampered = a2.clone();
+        assert!(tampered.txs[1].sign_input(0, &keypair(1), &[reward - 1], SigHashType::ALL));
+        assert_eq!(tampered.hash(), a2.hash());
+        assert_eq!(chain.accept_block(tampered, protocol::unix_time()).err(), Some(BlockError::BadMerkleRoot));
+        assert!(chain.get(&a2.hash()).is_none());
+
+        let update = add(&mut chain, &a2);
+        assert_eq!(update.connected, vec![a2.hash()]);
+        assert!(update.invalid.is_empty());
+    }
+
+    #[test]
+    fn headers_first() {
+        let mut source = chain();
+        let reward = source.params().block_reward(1);
+        let mut blocks = Vec::new();
+        for _ in 0..15 {
+            let tip = source.tip();
+            let block = child(&source, &tip, reward, vec![]);
+            add(&mut source, &block);
+            blocks.push(block);
+        }
+        let locator = source.locator(&source.tip());
+        assert_eq!(locator.len(), 12);
+        assert_eq!(locator[..10].to_vec(), blocks[5..].iter().rev().map(|b| b.hash()).collect::<Vec<_>>());
+        assert_eq!(locator[10..].to_vec(), vec![blocks[3].hash(), source.params().genesis_hash()]);
+
+        // Headers first, then the blocks in any order
+        let mut chain = chain();
+        let headers = source.headers_after(&chain.locator(&chain.tip()), &BlockHash::new(), 10);
+        assert_eq!(headers.len(), 10);
+        let now = protocol::unix_time();
+        for header in &headers {
+            chain.accept_header(header, now).unwrap();
+        }
+        let more = source.headers_after(&chain.locator(&chain.best_header()), &blocks[11].hash(), 10);
+        assert_eq!(more.iter().map(|h| h.hash()).collect::<Vec<_>>(), vec![blocks[10].hash(), blocks[11].hash()]);
+        for header in &more {
+            chain.accept_header(header, now).unwrap();
+        }
+        assert_eq!(chain.best_header(), blocks[11].hash());
+
+        assert_eq!(chain.missing_blocks(3), blocks[..3].iter().map(|b| b.hash()).collect::<Vec<_>>());
+        assert!(add(&mut chain, &blocks[1]).connected.is_empty());
+        assert_eq!(chain.missing_blocks(3), vec![blocks[0].hash(), blocks[2].hash()]);
+        let update = add(&mut chain, &blocks[0]);
+        assert_eq!(update.connected, vec![blocks[0].hash(), blocks[1].hash()]);
+        assert_eq!(chain.height(), 2);
+        assert_eq!(chain.missing_blocks(3), blocks[2..5].iter().map(|b| b.hash()).collect::<Vec<_>>());
+    }
+}
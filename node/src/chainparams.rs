@@ -0,0 +1,202 @@
+// Per-network consensus and networking parameters.
+//
+// Every network starts from its own genesis block and speaks with its own
+// magic string, so nodes on different networks never mistake each other for
+// peers. The network is picked once at startup with `select`; everything
+// else asks `params()`.
+//
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use pow;
+use protocol::{Block, BlockHash};
+use protocol::codec::Magic;
+use retarget::{Algorithm, RetargetParams};
+
+/// Smallest currency unit count in one coin
+pub const COIN: u64 = 100_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Network {
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Network::Mainnet => "mainnet",
+            &Network::Testnet => "testnet",
+            &Network::Regtest => "regtest",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub network: Network,
+    /// First bytes of every message on this network
+    pub magic: Magic,
+    /// Port nodes listen on by default
+    pub port: u16,
+    /// First byte of encoded addresses, c.f. `base58::encode_check`
+    pub address_version: u8,
+    /// Block every chain of this network starts from
+    pub genesis: Block,
+    pub retarget: RetargetParams,
+    /// Coins created by the first blocks' coinbase
+    pub initial_reward: u64,
+    /// Blocks between halvings of the block reward
+    pub halving_interval: u64,
+    /// Blocks a coinbase output must be buried under before it can be spent
+    pub coinbase_maturity: u64,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> ChainParams {
+        let retarget = RetargetParams {
+            algorithm: Algorithm::MovingAverage { window: 60 },
+            spacing: 60,
+            pow_limit: 0x1e00ffff,
+            max_adjust: 4,
+        };
+        ChainParams {
+            network: Network::Mainnet,
+            magic: *b"BLOCK",
+            port: 7878,
+            address_version: 0x19,
+            genesis: genesis(1535760000, retarget.pow_limit, 40651521),
+            retarget: retarget,
+            initial_reward: 50 * COIN,
+            halving_interval: 210_000,
+            coinbase_maturity: 100,
+        }
+    }
+
+    pub fn testnet() -> ChainParams {
+        let retarget = RetargetParams {
+            algorithm: Algorithm::Periodic { interval: 144 },
+            spacing: 60,
+            pow_limit: 0x1f00ffff,
+            max_adjust: 4,
+        };
+        ChainParams {
+            network: Network::Testnet,
+            magic: *b"BLTST",
+            port: 17878,
+            address_version: 0x6f,
+            genesis: genesis(1535760001, retarget.pow_limit, 150280),
+            retarget: retarget,
+            initial_reward: 50 * COIN,
+            halving_interval: 210_000,
+            coinbase_maturity: 100,
+        }
+    }
+
+    pub fn regtest() -> ChainParams {
+        let retarget = RetargetParams {
+            algorithm: Algorithm::Fixed,
+            spacing: 1,
+            pow_limit: pow::REGTEST_BITS,
+            max_adjust: 4,
+        };
+        ChainParams {
+            network: Network::Regtest,
+            magic: *b"BLREG",
+            port: 18878,
+            address_version: 0x7a,
+            genesis: genesis(1535760002, retarget.pow_limit, 1),
+            retarget: retarget,
+            initial_reward: 50 * COIN,
+            halving_interval: 150,
+            coinbase_maturity: 100,
+        }
+    }
+
+    pub fn for_network(network: Network) -> ChainParams {
+        match network {
+            Network::Mainnet => ChainParams::mainnet(),
+            Network::Testnet => ChainParams::testnet(),
+            Network::Regtest => ChainParams::regtest(),
+        }
+    }
+
+    pub fn genesis_hash(&self) -> BlockHash {
+        self.genesis.hash()
+    }
+
+    /// New coins a block at `height` may create
+    pub fn block_reward(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_reward >> halvings
+    }
+}
+
+/// Genesis blocks confirm no transactions and have no parent
+fn genesis(timestamp: u64, bits: u32, nonce: u64) -> Block {
+    let mut block = Block::new(BlockHash::new(), timestamp, bits, Vec::new());
+    block.header.nonce = nonce;
+    block
+}
+
+/// Index of the selected network, c.f. `NETWORKS`
+static SELECTED: AtomicUsize = AtomicUsize::new(0);
+
+const NETWORKS: [Network; 3] = [Network::Mainnet, Network::Testnet, Network::Regtest];
+
+/// Parameters of each network, built the first time they are asked for
+static PARAMS: [OnceLock<ChainParams>; 3] = [OnceLock::new(), OnceLock::new(), OnceLock::new()];
+
+/// Pick the network the node runs on; to be called once, at startup
+pub fn select(network: Network) {
+    let index = NETWORKS.iter().position(|&n| n == network).unwrap();
+    SELECTED.store(index, Ordering::SeqCst);
+}
+
+/// Network the node runs on; mainnet unless `select`ed otherwise
+pub fn network() -> Network {
+    NETWORKS[SELECTED.load(Ordering::SeqCst)]
+}
+
+/// Parameters of the selected network
+pub fn params() -> &'static ChainParams {
+    let index = SELECTED.load(Ordering::SeqCst);
+    PARAMS[index].get_or_init(|| ChainParams::for_network(NETWORKS[index]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::codec::MAGIC_LENGTH;
+
+    #[test]
+    fn genesis_blocks() {
+        let mut hashes = Vec::new();
+        for &network in NETWORKS.iter() {
+            let params = ChainParams::for_network(network);
+            let genesis = &params.genesis;
+            assert!(pow::check_proof(&genesis.hash(), genesis.header.bits), "{}", network.name());
+            assert_eq!(genesis.header.bits, params.retarget.pow_limit);
+            assert_eq!(params.magic.len(), MAGIC_LENGTH);
+            hashes.push((params.genesis_hash(), params.magic, params.address_version));
+        }
+        for i in 0..hashes.len() {
+            for j in 0..i {
+                assert!(hashes[i].0 != hashes[j].0 && hashes[i].1 != hashes[j].1 && hashes[i].2 != hashes[j].2);
+            }
+        }
+    }
+
+    #[test]
+    fn reward() {
+        let params = ChainParams::regtest();
+        assert_eq!(params.block_reward(0), 50 * COIN);
+        assert_eq!(params.block_reward(149), 50 * COIN);
+        assert_eq!(params.block_reward(150), 25 * COIN);
+        assert_eq!(params.block_reward(450), 625 * COIN / 100);
+        assert_eq!(params.block_reward(150 * 64), 0);
+    }
+}
@@ -0,0 +1,647 @@
+// The node's main loop.
+//
+// Everything that touches node state happens here, on a single thread:
+// peer session events come in through one channel and RPC tasks through
+// another. Other threads (peer sessions, the RPC server) only ever talk
+// to the daemon through those channels.
+//
+// Peers announce new blocks by hash, as we do ours, and blocks are pulled
+// headers-first, c.f. `sync`.
+//
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use assembler;
+use base58;
+use base64;
+use rmps;
+use chain::Chain;
+use chainparams::ChainParams;
+use mempool::{Mempool, MempoolError};
+use miner::Miner;
+use orphans;
+use orphans::OrphanPool;
+use peer::{PeerEvent, PeerId};
+use peer_manager::PeerManager;
+use protocol;
+use protocol::{Block, BlockHash, InvItem, MsgBlock, MsgInv, MsgGetData, MsgNotFound, MsgShareTx, Transaction, TxHash};
+use protocol::{MsgGetHeaders, MsgHeaders, MsgGetBlocks, MAX_HEADERS, MAX_GETBLOCKS};
+use protocol::codec::Message;
+use relay::Relay;
+use sync;
+use sync::Sync;
+use task::{Request, Task, TaskResult};
+use utxo::{OutPoint, UtxoView};
+use validation;
+use validation::{BlockError, TxError};
+use wallet;
+use Account;
+
+/// How often periodic jobs (e.g. opening outbound connections) run
+pub const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Upper bound on how long an RPC task waits for the loop to pick it up
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Daemon {
+    account: Account,
+    peers: PeerManager,
+    relay: Relay,
+    /// Transactions waiting to be mined, which we hand out to peers asking
+    mempool: Mempool,
+    /// Transactions and blocks waiting on their parents; transactions by wtxid
+    orphan_txs: OrphanPool<TxHash, Transaction>,
+    orphan_blocks: OrphanPool<BlockHash, Block>,
+    miner: Miner,
+    chain: Chain,
+    sync: Sync,
+}
+
+impl Daemon {
+    pub fn new(account: Account, peers: PeerManager, miner: Miner, mempool: Mempool, params: ChainParams) -> Daemon {
+        let chain = Chain::new(params);
+        peers.set_height(chain.height());
+        Daemon {
+            account: account,
+            peers: peers,
+            relay: Relay::new(),
+            mempool: mempool,
+            orphan_txs: OrphanPool::new(orphans::MAX_ORPHAN_TXS, orphans::ORPHAN_EXPIRY),
+            orphan_blocks: OrphanPool::new(orphans::MAX_ORPHAN_BLOCKS, orphans::ORPHAN_EXPIRY),
+            miner: miner,
+            chain: chain,
+            sync: Sync::new(),
+        }
+    }
+
+    /// Process events and tasks until every peer and RPC sender is gone
+    pub fn run(&mut self, events: Receiver<PeerEvent>, tasks: Receiver<Request>) {
+        let mut last_tick = Instant::now();
+        self.peers.tick();
+
+        loop {
+            match events.recv_timeout(POLL_INTERVAL) {
+                Ok(event) => {
+                    match self.peers.handle_event(event) {
+                        Some(PeerEvent::Connected(id, _)) => {
+                            self.relay.add_peer(id);
+                            self.sync.add_peer(id);
+                            self.request_headers(id);
+                        },
+                        Some(PeerEvent::Disconnected(id, _)) => {
+                            let retries = self.relay.remove_peer(id);
+                            self.request(retries);
+                            self.sync.remove_peer(id);
+                            self.request_blocks();
+                        },
+                        Some(PeerEvent::Message(id, msg)) => self.handle_message(id, msg),
+                        _ => ()
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break
+            }
+
+            while let Ok(req) = tasks.try_recv() {
+                let result = self.handle_task(req.task);
+                let _ = req.reply.send(result);
+            }
+
+            while let Some(block) = self.miner.try_recv() {
+                self.mined(block);
+            }
+
+            if last_tick.elapsed() >= TICK_INTERVAL {
+                self.peers.tick();
+                let retries = self.relay.expire();
+                self.request(retries);
+                let expired = self.mempool.expire(protocol::unix_time());
+                if expired > 0 {
+                    println!("Expired {} transactions from the mempool", expired);
+                }
+                self.orphan_txs.expire();
+                self.orphan_blocks.expire();
+                for peer in self.sync.stalled() {
+                    println!("Peer {} stalled block download, disconnecting", peer);
+                    if let Some(handle) = self.peers.peers().get(peer) {
+                        handle.disconnect();
+                    }
+                    self.sync.remove_peer(peer);
+                }
+                self.request_blocks();
+                last_tick = Instant::now();
+            }
+        }
+    }
+
+    fn handle_message(&mut self, peer: PeerId, msg: Message) {
+        match msg {
+            Message::Inv(inv) => self.fetch(peer, &inv.items),
+            Message::GetData(req) => {
+                let mut found = Vec::new();
+                let mut missing = Vec::new();
+                for item in req.items {
+                    match item {
+                        InvItem::Tx(ref hash) => match self.mempool.get(hash) {
+                            Some(tx) => found.push(tx.clone()),
+                            None => missing.push(item)
+                        },
+                        InvItem::Block(ref hash) => match self.chain.block(hash) {
+                            Some(block) => {
+                                self.peers.peers().send(peer, Message::Block(MsgBlock { block: block.clone() }));
+                            },
+                            None => missing.push(item)
+                        }
+                    }
+                }
+                if !found.is_empty() {
+                    self.peers.peers().send(peer, Message::ShareTx(MsgShareTx { txs: found }));
+                }
+                if !missing.is_empty() {
+                    self.peers.peers().send(peer, Message::NotFound(MsgNotFound { items: missing }));
+                }
+            },
+            Message::NotFound(nf) => {
+                let retries: Vec<_> = nf.items.into_iter()
+                    .filter_map(|item| self.relay.not_found(peer, item))
+                    .collect();
+                self.request(retries);
+            },
+            Message::ShareTx(share) => {
+                for tx in share.txs {
+                    self.relay.received(peer, InvItem::Tx(tx.hash()));
+                    self.receive_tx(tx, peer);
+                }
+            },
+            Message::Block(msg) => {
+                let hash = msg.block.hash();
+                self.relay.received(peer, InvItem::Block(hash));
+                self.sync.block_received(&hash);
+                if self.chain.has_block(&hash) || self.orphan_blocks.contains(&hash) {
+                    return;
+                }
+                self.receive_block(msg.block, peer);
+                self.request_blocks();
+            },
+            Message::GetHeaders(req) => {
+                let headers = self.chain.headers_after(&req.locator, &req.stop, MAX_HEADERS);
+                self.peers.peers().send(peer, Message::Headers(MsgHeaders { headers: headers }));
+            },
+            Message::Headers(msg) => {
+                self.sync.headers_received(peer);
+                if msg.headers.len() > MAX_HEADERS {
+                    self.peers.misbehaving(peer, validation::PENALTY_INVALID, "too many headers");
+                    return;
+                }
+                let now = protocol::unix_time();
+                for header in &msg.headers {
+                    if let Err(e) = self.chain.accept_header(header, now) {
+                        println!("Peer {} sent invalid header {:?}: {}", peer, header.hash(), e);
+                        self.peers.misbehaving(peer, e.penalty(), &format!("{}", e));
+                        return;
+                    }
+                }
+                // A full message means there are more
+                if msg.headers.len() == MAX_HEADERS {
+                    self.request_headers(peer);
+                }
+                // Orphans whose parent we now know can be stored
+                for header in &msg.headers {
+                    for (block, from) in self.orphan_blocks.take_children(&header.hash()) {
+                        self.receive_block(block, from);
+                    }
+                }
+                self.request_blocks();
+            },
+            Message::GetBlocks(req) => {
+                for hash in req.hashes.iter().take(MAX_GETBLOCKS) {
+                    if let Some(block) = self.chain.block(hash) {
+                        self.peers.peers().send(peer, Message::Block(MsgBlock { block: block.clone() }));
+                    }
+                }
+            },
+            msg => protocol::handle_message(peer, msg)
+        }
+    }
+
+    /// Ask a peer for the items it has that we don't
+    fn fetch(&mut self, peer: PeerId, items: &[InvItem]) {
+        // Blocks come headers-first: a block we don't know the header of
+        // means the peer is ahead of us
+        let mut blocks = false;
+        let mut ahead = false;
+        for item in items {
+            if let &InvItem::Block(ref hash) = item {
+                blocks = true;
+                ahead = ahead || self.chain.get(hash).is_none();
+            }
+        }
+
+        // An orphan with the same txid may be a corrupted copy, so only
+        // what is in the mempool counts
+        let mempool = &self.mempool;
+        let wanted = self.relay.announced(peer, items, |item| match item {
+            &InvItem::Tx(ref hash) => mempool.contains(hash),
+            &InvItem::Block(_) => true
+        });
+        if !wanted.is_empty() {
+            self.peers.peers().send(peer, Message::GetData(MsgGetData { items: wanted }));
+        }
+        if ahead {
+            self.request_headers(peer);
+        } else if blocks {
+            self.request_blocks();
+        }
+    }
+
+    /// Add a transaction from a peer to the mempool, then the orphans that
+    /// were waiting on it
+    fn receive_tx(&mut self, tx: Transaction, peer: PeerId) {
+        let height = self.chain.height() + 1;
+        let mut queue = vec![(tx, peer)];
+        while let Some((tx, from)) = queue.pop() {
+            let hash = tx.hash();
+            match self.mempool.add(tx.clone(), self.chain.utxos(), height, self.chain.params(), protocol::unix_time()) {
+                Ok(_) => {
+                    self.announce(InvItem::Tx(hash));
+                    queue.extend(self.orphan_txs.take_children(&hash));
+                },
+                Err(MempoolError::AlreadyKnown) => (),
+                Err(MempoolError::Invalid(TxError::MissingInput(_))) => self.add_orphan_tx(tx, from),
+                Err(e) => println!("Rejecting transaction {:?} from peer {}: {}", hash, from, e)
+            }
+        }
+    }
+
+    /// Keep a transaction until the ones it spends arrive, asking the peer
+    /// that sent it for them
+    fn add_orphan_tx(&mut self, tx: Transaction, peer: PeerId) {
+        let mut parents: Vec<TxHash> = {
+            let view = self.mempool.view(self.chain.utxos(), self.chain.height() + 1);
+            tx.inputs().iter()
+                .filter(|input| !view.contains(&OutPoint { tx: input.tx, index: input.index }))
+                .map(|input| input.tx)
+                .collect()
+        };
+        parents.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        parents.dedup();
+        // Nothing to wait for, e.g. it spends what it would replace
+        if parents.is_empty() {
+            return;
+        }
+
+        let items: Vec<InvItem> = parents.iter().map(|parent| InvItem::Tx(*parent)).collect();
+        if self.orphan_txs.add(tx.wtxid(), tx, parents, peer) {
+            self.fetch(peer, &items);
+        }
+    }
+
+    /// Add a block from a peer to the chain, then the orphans that were
+    /// waiting on it
+    fn receive_block(&mut self, block: Block, peer: PeerId) {
+        let mut queue = vec![(block, peer)];
+        while let Some((block, from)) = queue.pop() {
+            let hash = block.hash();
+            let prev = block.header.prev_hash;
+            if self.chain.get(&prev).is_none() {
+                // Only keep blocks that could be valid
+                if let Err(e) = validation::check_block(&block, protocol::unix_time()) {
+                    println!("Peer {} sent invalid block {:?}: {}", from, hash, e);
+                    self.peers.misbehaving(from, e.penalty(), &format!("{}", e));
+                    continue;
+                }
+                self.orphan_blocks.add(hash, block, vec![prev], from);
+                // The peer is ahead of us; catch up on its headers
+                self.request_headers(from);
+                continue;
+            }
+            match self.accept_block(block) {
+                Ok(()) => {
+                    println!("Accepted block {:?} from peer {}, height now {}", hash, from, self.chain.height());
+                    queue.extend(self.orphan_blocks.take_children(&hash));
+                },
+                Err(e) => {
+                    println!("Peer {} sent invalid block {:?}: {}", from, hash, e);
+                    self.peers.misbehaving(from, e.penalty(), &format!("{}", e));
+                }
+            }
+        }
+    }
+
+    /// Amounts of the outputs a mempool transaction spends, confirmed or not
+    fn spent_amounts(&self, tx: &Transaction) -> Vec<u64> {
+        tx.inputs().iter().map(|input| {
+            let outpoint = OutPoint { tx: input.tx, index: input.index };
+            match self.chain.utxos().get(&outpoint) {
+                Some(entry) => entry.output.amount,
+                None => self.mempool.get(&input.tx)
+                    .and_then(|parent| parent.outputs().get(input.index as usize))
+                    .map_or(0, |output| output.amount)
+            }
+        }).collect()
+    }
+
+    /// Ask peers for items, one `getdata` per peer
+    fn request(&mut self, items: Vec<(PeerId, InvItem)>) {
+        let mut by_peer: HashMap<PeerId, Vec<InvItem>> = HashMap::new();
+        for (peer, item) in items {
+            by_peer.entry(peer).or_default().push(item);
+        }
+        for (peer, items) in by_peer {
+            self.peers.peers().send(peer, Message::GetData(MsgGetData { items: items }));
+        }
+    }
+
+    /// Ask a peer for the headers following our best one
+    fn request_headers(&mut self, peer: PeerId) {
+        let locator = self.chain.locator(&self.chain.best_header());
+        let msg = MsgGetHeaders { locator: locator, stop: BlockHash::new() };
+        if self.peers.peers().send(peer, Message::GetHeaders(msg)) {
+            self.sync.headers_requested(peer);
+        }
+    }
+
+    /// Ask peers for the blocks we have headers of, as far as the window
+    /// goes, fastest peers first
+    fn request_blocks(&mut self) {
+        let missing = self.chain.missing_blocks(sync::WINDOW);
+        let fastest = self.peers.peers_by_latency();
+        for (peer, hashes) in self.sync.schedule(&missing, &fastest) {
+            self.peers.peers().send(peer, Message::GetBlocks(MsgGetBlocks { hashes: hashes }));
+        }
+    }
+
+    /// Let every peer that doesn't have it yet know about an item
+    fn announce(&mut self, item: InvItem) {
+        for peer in self.relay.announce(item) {
+            self.peers.peers().send(peer, Message::Inv(MsgInv { items: vec![item] }));
+        }
+    }
+
+    /// Block to mine on top of the current tip, paying to our account
+    fn block_template(&self) -> assembler::Template {
+        assembler::assemble(&self.chain, &self.mempool, self.account.address(), protocol::unix_time())
+    }
+
+    /// A new tip arrived; anything being mined on the old one is stale, and
+    /// peers connecting from now on should hear about the new height
+    fn new_tip(&mut self) {
+        self.peers.set_height(self.chain.height());
+        if self.miner.is_running() {
+            let template = self.block_template();
+            self.miner.start(template.block);
+        }
+    }
+
+    fn mined(&mut self, block: Block) {
+        // Found too late, the tip moved on in the meantime
+        if block.header.prev_hash != self.chain.tip() {
+            return;
+        }
+        let hash = block.hash();
+        let count = block.txs.len();
+        match self.accept_block(block) {
+            Ok(()) => println!("Mined block {:?} at height {} with {} transactions", hash, self.chain.height(), count),
+            Err(e) => println!("Rejecting mined block: {}", e)
+        }
+    }
+
+    /// Add a block to the chain, following the best chain if it changed.
+    /// Fails if the block, or one it made us try to connect, is invalid.
+    fn accept_block(&mut self, block: Block) -> Result<(), BlockError> {
+        let hash = block.hash();
+        let update = self.chain.accept_block(block, protocol::unix_time())?;
+
+        let mut result = Ok(());
+        for (invalid, e) in update.invalid {
+            println!("Block {:?} is invalid: {}", invalid, e);
+            if result.is_ok() {
+                result = Err(if invalid == hash { e } else { BlockError::InvalidAncestor(invalid) });
+            }
+        }
+        if update.connected.is_empty() {
+            return result;
+        }
+        if !update.disconnected.is_empty() {
+            println!("Reorganized: {} blocks disconnected, {} connected", update.disconnected.len(), update.connected.len());
+        }
+
+        // Confirmed transactions leave the pool. After a reorg, the pool
+        // is rebuilt on the new chain: transactions of disconnected blocks
+        // first, as pool ones may spend them
+        if update.disconnected.is_empty() {
+            for connected in &update.connected {
+                self.mempool.remove_for_block(self.chain.block(connected).unwrap());
+            }
+        } else {
+            let now = protocol::unix_time();
+            let pending = self.mempool.drain();
+            let height = self.chain.height() + 1;
+            let txs = update.unconfirmed.into_iter().map(|tx| (tx, now))
+                .chain(pending.into_iter().map(|entry| (entry.tx, entry.time)));
+            for (tx, time) in txs {
+                let _ = self.mempool.add(tx, self.chain.utxos(), height, self.chain.params(), time);
+            }
+        }
+
+        // Orphan transactions may have been waiting on confirmed ones
+        let mut adopted = Vec::new();
+        for connected in &update.connected {
+            for tx in &self.chain.block(connected).unwrap().txs {
+                adopted.extend(self.orphan_txs.take_children(&tx.hash()));
+            }
+        }
+        for (tx, from) in adopted {
+            self.receive_tx(tx, from);
+        }
+
+        // Peers fetch what they miss of the new chain themselves
+        let tip = self.chain.tip();
+        self.announce(InvItem::Block(tip));
+        self.new_tip();
+        result
+    }
+
+    fn mining_info(&self) -> TaskResult {
+        TaskResult::MiningInfo {
+            mining: self.miner.is_running(),
+            threads: self.miner.threads(),
+            height: self.chain.height()
+        }
+    }
+
+    fn handle_task(&mut self, task: Task) -> TaskResult {
+        match task {
+            Task::DumpPrivKey => {
+                TaskResult::PrivKey(base58::encode(&self.account.secret.to_bytes()[..]))
+            },
+            Task::GetNetworkInfo => {
+                TaskResult::NetworkInfo { num_nodes: self.peers.peers().len() }
+            },
+            Task::GetPeerInfo => {
+                TaskResult::PeerInfo(self.peers.peer_info())
+            },
+            Task::StartMining => {
+                if !self.miner.is_running() {
+                    let template = self.block_template();
+                    self.miner.start(template.block);
+                }
+                self.mining_info()
+            },
+            Task::StopMining => {
+                self.miner.stop();
+                self.mining_info()
+            },
+            Task::GetSyncInfo => {
+                let best = self.chain.best_header();
+                TaskResult::SyncInfo {
+                    headers: self.chain.get(&best).unwrap().height,
+                    blocks: self.chain.height(),
+                    in_flight: self.sync.in_flight()
+                }
+            },
+            Task::GetBlockTemplate => {
+                let template = self.block_template();
+                let header = &template.block.header;
+                TaskResult::BlockTemplate {
+                    height: template.height,
+                    prev_hash: format!("{:?}", header.prev_hash),
+                    timestamp: header.timestamp,
+                    bits: header.bits,
+                    fees: template.fees,
+                    transactions: template.block.txs.len(),
+                    block: rmps::to_vec(&template.block).map(|buf| base64::encode(&buf)).unwrap_or_default()
+                }
+            },
+            Task::BumpFee { tx, fee_rate } => {
+                let (original, fee) = match self.mempool.entry(&tx) {
+                    Some(entry) => (entry.tx.clone(), entry.fee),
+                    None => return TaskResult::Error { error: format!("transaction {:?} is not in the mempool", tx) }
+                };
+                let new_fee = self.mempool.replacement_fee(&tx, fee_rate).unwrap();
+                let amounts = self.spent_amounts(&original);
+                let bumped = match wallet::bump_fee(&original, fee, new_fee, self.account.keypair(), &amounts) {
+                    Ok(bumped) => bumped,
+                    Err(e) => return TaskResult::Error { error: format!("{}", e) }
+                };
+                let height = self.chain.height() + 1;
+                match self.mempool.add(bumped, self.chain.utxos(), height, self.chain.params(), protocol::unix_time()) {
+                    Ok(hash) => {
+                        self.announce(InvItem::Tx(hash));
+                        TaskResult::Bumped { tx: format!("{:?}", hash), fee: new_fee }
+                    },
+                    Err(e) => TaskResult::Error { error: format!("{}", e) }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc::channel;
+    use ed25519_dalek::SECRET_KEY_LENGTH;
+    use addrbook::AddrBook;
+    use mempool::{DEFAULT_EXPIRY, DEFAULT_MAX_SIZE, DEFAULT_MIN_FEE_RATE};
+    use miner;
+    use protocol::SigHashType;
+    use test_util::{keypair, spend};
+
+    /// Daemon on a fresh regtest chain, its account being `test_util`'s key 1
+    fn daemon() -> Daemon {
+        let mut params = ChainParams::regtest();
+        params.coinbase_maturity = 1;
+        let account = Account::from_bytes(&String::from("test"), &[1; SECRET_KEY_LENGTH]);
+        let local = protocol::MsgVersion::new(protocol::SERVICE_NETWORK, 0, 0, 0, params.genesis_hash());
+        let book = AddrBook::new(&env::temp_dir().join("daemon-tests.dat"));
+        let peers = PeerManager::new(0, local, book, channel().0);
+        let pool = Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, DEFAULT_MIN_FEE_RATE);
+        Daemon::new(account, peers, Miner::new(0), pool, params)
+    }
+
+    /// Mine the daemon's current template
+    fn mine(daemon: &mut Daemon) -> Block {
+        let mut block = daemon.block_template().block;
+        block.header.nonce = miner::search(&block.header, 0, 1, &AtomicBool::new(false)).unwrap();
+        daemon.mined(block.clone());
+        block
+    }
+
+    #[test]
+    fn sync_info() {
+        let mut daemon = daemon();
+        match daemon.handle_task(Task::GetSyncInfo) {
+            TaskResult::SyncInfo { headers, blocks, in_flight } => assert_eq!((headers, blocks, in_flight), (0, 0, 0)),
+            result => panic!("unexpected {:?}", result)
+        }
+        mine(&mut daemon);
+        match daemon.handle_task(Task::GetSyncInfo) {
+            TaskResult::SyncInfo { headers, blocks, .. } => assert_eq!((headers, blocks), (1, 1)),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn block_template() {
+        let mut daemon = daemon();
+        let genesis = daemon.chain.tip();
+        let (height, prev_hash, transactions, block) = match daemon.handle_task(Task::GetBlockTemplate) {
+            TaskResult::BlockTemplate { height, prev_hash, transactions, block, .. } => (height, prev_hash, transactions, block),
+            result => panic!("unexpected {:?}", result)
+        };
+        assert_eq!((height, transactions), (1, 1));
+        assert_eq!(prev_hash, format!("{:?}", genesis));
+        let block: Block = rmps::from_slice(&base64::decode(&block).unwrap()).unwrap();
+        assert_eq!(block.header.prev_hash, genesis);
+        assert_eq!(block.txs[0].outputs()[0].creditor, daemon.account.address());
+    }
+
+    #[test]
+    fn bump_fee() {
+        let mut daemon = daemon();
+        match daemon.handle_task(Task::BumpFee { tx: TxHash::new(), fee_rate: 1000 }) {
+            TaskResult::Error { .. } => (),
+            result => panic!("unexpected {:?}", result)
+        }
+
+        let block = mine(&mut daemon);
+        let reward = block.txs[0].outputs()[0].amount;
+        let tx = spend(1, &[(block.txs[0].hash(), 0, reward)], &[reward - 1000]);
+        let hash = daemon.mempool.add(tx, daemon.chain.utxos(), 2, daemon.chain.params(), protocol::unix_time()).unwrap();
+
+        let expected = daemon.mempool.replacement_fee(&hash, 100_000).unwrap();
+        let (bumped, fee) = match daemon.handle_task(Task::BumpFee { tx: hash, fee_rate: 100_000 }) {
+            TaskResult::Bumped { tx, fee } => (tx, fee),
+            result => panic!("unexpected {:?}", result)
+        };
+        assert_eq!(fee, expected);
+        assert!(daemon.mempool.get(&hash).is_none());
+        assert_eq!(daemon.mempool.len(), 1);
+        assert_ne!(bumped, format!("{:?}", hash));
+    }
+
+    #[test]
+    fn orphan_copies() {
+        let mut daemon = daemon();
+        let block = mine(&mut daemon);
+        let reward = block.txs[0].outputs()[0].amount;
+        let parent = spend(1, &[(block.txs[0].hash(), 0, reward)], &[reward - 1000]);
+        let child = spend(1, &[(parent.hash(), 0, reward - 1000)], &[reward - 2000]);
+
+        // A copy with a bad signature arriving first doesn't crowd out the
+        // genuine orphan
+        let mut tampered = child.clone();
+        assert!(tampered.sign_input(0, &keypair(1), &[reward], SigHashType::ALL));
+        daemon.receive_tx(tampered, 0);
+        daemon.receive_tx(child.clone(), 1);
+        assert_eq!(daemon.orphan_txs.len(), 2);
+
+        daemon.receive_tx(parent.clone(), 1);
+        assert_eq!(daemon.orphan_txs.len(), 0);
+        assert!(daemon.mempool.contains(&parent.hash()));
+        assert_eq!(daemon.mempool.get(&child.hash()).map(|tx| tx.wtxid()), Some(child.wtxid()));
+    }
+}
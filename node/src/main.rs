@@ -1,5 +1,11 @@
 #![feature(plugin)]
 #![plugin(rocket_codegen)]
+// The codebase keeps explicit field inits, reference patterns and the
+// numeric `max_value()` constructors; the other lints would need APIs newer
+// than the toolchain the node builds with
+#![allow(clippy::redundant_field_names, clippy::match_ref_pats, clippy::needless_borrowed_reference)]
+#![allow(clippy::legacy_numeric_constants, clippy::new_without_default, clippy::len_without_is_empty)]
+#![allow(clippy::io_other_error, clippy::manual_div_ceil, clippy::manual_is_multiple_of, clippy::unnecessary_map_or)]
 extern crate rand;
 extern crate sha2;
 extern crate ed25519_dalek;
@@ -7,41 +13,62 @@ extern crate ed25519_dalek;
 extern crate serde;
 extern crate rmp;
 extern crate rmp_serde as rmps;
-#[macro_use] extern crate byteorder;
+extern crate byteorder;
 #[macro_use] extern crate shrinkwraprs;
 extern crate ipnet;
+extern crate num_cpus;
+extern crate base64;
 
 // Crate-level modules
 pub mod method;
 pub mod rpc;
 pub mod base58;
 pub mod protocol;
+pub mod peer;
+pub mod peer_manager;
+pub mod addrbook;
+pub mod task;
+pub mod relay;
+pub mod pow;
+pub mod retarget;
+pub mod chainparams;
+pub mod utxo;
+pub mod validation;
+pub mod chain;
+pub mod mempool;
+pub mod orphans;
+pub mod assembler;
+pub mod sync;
+pub mod miner;
+pub mod wallet;
+pub mod daemon;
+#[cfg(test)]
+mod test_util;
 
 use std::fs;
 use std::fs::File;
-use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::path::Path;
+use std::str::FromStr;
 use std::env;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
-use std::thread;
-use ed25519_dalek::{SECRET_KEY_LENGTH, PUBLIC_KEY_LENGTH, PublicKey, SecretKey, Signature};
+use std::net::{TcpListener, SocketAddr};
+use std::sync::mpsc::channel;
+use ed25519_dalek::{SECRET_KEY_LENGTH, PublicKey, SecretKey};
 use rand::OsRng;
-use byteorder::{ReadBytesExt, BigEndian, LittleEndian, NetworkEndian};
 
 
 // Owned account; not the same thing as a random
 // 'account' in the network
 //
-struct Account {
+pub struct Account {
     name: String,
     secret: SecretKey
 }
 
 impl Account {
-    fn from_bytes(name: &String, buf: &[u8; SECRET_KEY_LENGTH]) -> Account {
+    fn from_bytes(name: &str, buf: &[u8; SECRET_KEY_LENGTH]) -> Account {
         Account {
-            name: name.clone(),
+            name: name.to_string(),
             secret: match SecretKey::from_bytes(buf) {
                 Ok(kp) => kp,
                 Err(_) => panic!("Secret key data is malformed!")
@@ -100,26 +127,56 @@ impl Account {
         ed25519_dalek::PublicKey::from_secret::<sha2::Sha512>(&self.secret)
     }
 
+    /// Keys to sign the account's transactions with
+    fn keypair(&self) -> ed25519_dalek::Keypair {
+        ed25519_dalek::Keypair {
+            secret: SecretKey::from_bytes(&self.secret.to_bytes()).unwrap(),
+            public: self.public_key()
+        }
+    }
+
+    /// Address other users send coins to
+    pub fn address(&self) -> protocol::Address {
+        protocol::Address::from_public_key(&self.public_key())
+    }
+
 }
 
 
+/// Value of a `--name=value` flag, if given and well-formed
+fn flag_value<T: FromStr>(flags: &[String], name: &str) -> Option<T> {
+    let prefix = format!("--{}=", name);
+    flags.iter()
+        .find(|f| f.starts_with(&prefix))
+        .and_then(|f| f[prefix.len()..].parse().ok())
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let (flags, args): (Vec<String>, Vec<String>) = env::args().partition(|a| a.starts_with("--"));
     if args.len() < 3 {
-        panic!("Need arguments <root> <account_name>!");
+        panic!("Need arguments [--testnet|--regtest] [--maxmempool=<MB>] [--mempoolexpiry=<hours>] [--minrelayfee=<units per kB>] <root> <account_name> [<node_addr>...]!");
     }
 
+    // Regression-test mode: blocks are mined instantly
+    let network = if flags.iter().any(|f| f == "--regtest") {
+        chainparams::Network::Regtest
+    } else if flags.iter().any(|f| f == "--testnet") {
+        chainparams::Network::Testnet
+    } else {
+        chainparams::Network::Mainnet
+    };
+    chainparams::select(network);
+    let params = chainparams::params();
+    println!("Running on {}, genesis block {:?}", network.name(), params.genesis_hash());
+
     let root = Path::new(&args[1]);
     println!("Using root folder `{}`...", root.display());
 
     // Create root folder if it does not exist
     //
-    match fs::create_dir_all(&root) {
-        Err(_) => {
-            panic!("Could not create root folder!");
-        },
-        _ => ()
-    };
+    if fs::create_dir_all(root).is_err() {
+        panic!("Could not create root folder!");
+    }
 
 
     // Set secret key file
@@ -134,20 +191,28 @@ fn main() {
         &secret
     );
 
-    println!("Using account `{}` with public key `{}` ...",
+    println!("Using account `{}` with address `{}` ...",
         account.name,
-        base58::encode(&account.public_key().to_bytes()[..])
+        account.address()
     );
 
     // Start local JSON-RPC server (user-to-node comm)
-    let mut rpc = rpc::Server::new();
+    let (tasks_tx, tasks_rx) = channel();
+    let mut rpc = rpc::Server::new(tasks_tx);
     rpc.add_method(method::DumpPrivKey);
+    rpc.add_method(method::GetNetworkInfo);
+    rpc.add_method(method::GetPeerInfo);
+    rpc.add_method(method::StartMining);
+    rpc.add_method(method::StopMining);
+    rpc.add_method(method::GetSyncInfo);
+    rpc.add_method(method::GetBlockTemplate);
+    rpc.add_method(method::BumpFee);
     rpc.run();
 
 
     // Start good-old TCP server (node-to-node comm)
     let listener: TcpListener;
-    let mut port = 7878;
+    let mut port = params.port;
     loop {
         match TcpListener::bind(format!("127.0.0.1:{}", port)) {
             Ok(l) => { listener = l; break; },
@@ -157,38 +222,40 @@ fn main() {
 
     println!("Listening at {}...", port);
 
-    for stream in listener.incoming() {
-        handle_connection(stream.unwrap());
+    // Every peer session reports to the daemon through a single channel,
+    // so the main thread is the only one touching protocol state
+    let (events_tx, events_rx) = channel();
+    // The height is that of the chain when each session opens, c.f.
+    // `PeerManager::set_height`
+    let local = protocol::MsgVersion::new(protocol::SERVICE_NETWORK, 0, rand::random(), port, params.genesis_hash());
+
+    // Networks other than mainnet keep their state in a subfolder
+    let data = match network {
+        chainparams::Network::Mainnet => root.to_path_buf(),
+        _ => root.join(network.name())
+    };
+    if fs::create_dir_all(&data).is_err() {
+        panic!("Could not create data folder `{}`!", data.display());
     }
-}
-
-
-fn handle_connection(mut stream: TcpStream) {
-    // Protocol message format:
-    // "BLOCK" | 4 byte version | 4 byte msg. type | 4 bytes payload size | payload
-    // Message payload is MessagePack-encoded
-
-    const MAGIC_SIZE: usize = 5;
-    let mut magic: [u8; MAGIC_SIZE] = [0; MAGIC_SIZE];
-    stream.read_exact(&mut magic).unwrap();
-    if &magic[..] != b"BLOCK" {
-        println!("Message header is not `BLOCK`!");
-        return
+    let book = addrbook::AddrBook::open(&data.join("peers.dat"));
+    println!("Loaded {} node addresses", book.len());
+    let mut peers = peer_manager::PeerManager::new(peer_manager::TARGET_OUTBOUND, local, book, events_tx);
+    peers.listen(listener);
+
+    // Remaining arguments are nodes to connect to
+    for arg in &args[3..] {
+        match arg.parse::<SocketAddr>() {
+            Ok(addr) => peers.add_known(&protocol::Node::from_socket_addr(&addr)),
+            Err(_) => println!("Ignoring malformed node address `{}`", arg)
+        }
     }
 
-    // We don't really take versions seriously for now -- but just for the sake of it,
-    // having this field from the very start gives us margin for the future
-    let v = stream.read_u32::<NetworkEndian>().unwrap();
-    println!("Message refers to protocol version {}", v);
-
-    let t = stream.read_u32::<NetworkEndian>().unwrap();
-    println!("Message type is {}", t);
+    // Transactions waiting to be mined
+    let max_size = flag_value::<usize>(&flags, "maxmempool").map_or(mempool::DEFAULT_MAX_SIZE, |mb| mb * 1_000_000);
+    let expiry = flag_value::<u64>(&flags, "mempoolexpiry").map_or(mempool::DEFAULT_EXPIRY, |hours| hours * 60 * 60);
+    let min_fee_rate = flag_value::<u64>(&flags, "minrelayfee").unwrap_or(mempool::DEFAULT_MIN_FEE_RATE);
+    let pool = mempool::Mempool::new(max_size, expiry, min_fee_rate);
 
-    let sz = stream.read_u32::<NetworkEndian>().unwrap();
-    println!("Payload size is {}", sz);
-
-    let mut buffer = Vec::new();
-    stream.take(sz.into()).read_to_end(&mut buffer).unwrap();
-    protocol::handle_message(v, t, &buffer.as_slice());
+    let mut daemon = daemon::Daemon::new(account, peers, miner::Miner::new(0), pool, params.clone());
+    daemon.run(events_rx, tasks_rx);
 }
-
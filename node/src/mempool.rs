@@ -0,0 +1,651 @@
+// Transaction memory pool.
+//
+// Unconfirmed transactions wait here to be relayed and mined. A transaction
+// gets in only if it is valid against the UTXO set plus the outputs of other
+// pool transactions, so chains of unconfirmed transactions are fine, but no
+// two pool transactions may spend the same output.
+//
+// The pool is capped in bytes. Past the cap, the package paying the least per
+// byte goes first: a transaction together with its descendants (those spending
+// its outputs, directly or not), which could not be mined without it.
+// Transactions also expire after a while, and leave the pool once a block
+// confirms them or spends one of their inputs.
+//
+// A transaction spending outputs that pool transactions already spend may
+// replace them (replace-by-fee), so a sender can bump a fee set too low. It
+// must pay more in total than everything it evicts, the conflicting
+// transactions and their descendants, and more per byte than the conflicting
+// transactions themselves.
+//
+// Relay isn't free: every transaction must pay at least the pool's minimum fee
+// rate, and a replacement must pay that rate for its own size on top of the
+// fees it evicts. Otherwise a peer could fill the pool, or churn it with
+// replacements, at no cost.
+//
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use rmps;
+use chainparams::ChainParams;
+use protocol::{Block, Transaction, TxHash};
+use utxo::{OutPoint, UtxoEntry, UtxoView};
+use validation;
+use validation::TxError;
+
+/// Default size cap, in encoded bytes
+pub const DEFAULT_MAX_SIZE: usize = 50_000_000;
+
+/// Default time a transaction may wait to be mined, in seconds
+pub const DEFAULT_EXPIRY: u64 = 14 * 24 * 60 * 60;
+
+/// Most transactions a single replacement may evict
+pub const MAX_REPLACED: usize = 100;
+
+/// Currency units paid per 1000 encoded bytes
+pub type FeeRate = u64;
+
+/// Default least fee rate a transaction must pay to get in
+pub const DEFAULT_MIN_FEE_RATE: FeeRate = 1000;
+
+pub fn fee_rate(fee: u64, size: usize) -> FeeRate {
+    let rate = fee as u128 * 1000 / size.max(1) as u128;
+    if rate > u64::max_value() as u128 { u64::max_value() } else { rate as u64 }
+}
+
+/// Least fee for `size` bytes to pay at least `rate`
+pub fn fee_at(rate: FeeRate, size: usize) -> u64 {
+    let fee = (rate as u128 * size as u128 + 999) / 1000;
+    cmp::min(fee, u64::max_value() as u128) as u64
+}
+
+/// Encoded size of a transaction
+pub fn encoded_size(tx: &Transaction) -> usize {
+    rmps::to_vec(tx).map(|buf| buf.len()).unwrap_or(usize::max_value())
+}
+
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: u64,
+    /// Encoded size in bytes
+    pub size: usize,
+    /// Unix time the transaction entered the pool
+    pub time: u64,
+    /// Arrival order; parents always come before their children
+    sequence: u64,
+    /// Fees and size of the transaction and its descendants together
+    package_fee: u64,
+    package_size: usize,
+}
+
+impl MempoolEntry {
+    pub fn fee_rate(&self) -> FeeRate {
+        fee_rate(self.fee, self.size)
+    }
+
+    /// Where the entry goes in `Mempool::by_package_rate`
+    fn package_key(&self) -> (FeeRate, u64) {
+        (fee_rate(self.package_fee, self.package_size), self.sequence)
+    }
+}
+
+/// Why a transaction was not let into the pool
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+    /// The transaction is in the pool already
+    AlreadyKnown,
+    /// A replacement would evict this many transactions, more than `MAX_REPLACED`
+    TooManyReplaced(usize),
+    /// Transaction pays less than the minimum fee rate
+    FeeTooLow { rate: FeeRate, min: FeeRate },
+    /// A replacement doesn't pay the fees of the transactions it evicts
+    /// plus the minimum fee rate for its own size
+    ReplacementFee { fee: u64, required: u64 },
+    /// A replacement doesn't pay more per byte than a transaction it conflicts with
+    ReplacementFeeRate { rate: FeeRate, replaced: FeeRate },
+    /// The transaction is invalid against the UTXO set and the pool
+    Invalid(TxError),
+    /// The pool is full of transactions paying better
+    Full,
+}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &MempoolError::AlreadyKnown => write!(f, "transaction is already in the pool"),
+            &MempoolError::TooManyReplaced(n) => write!(f, "replacement would evict {} transactions, at most {} allowed", n, MAX_REPLACED),
+            &MempoolError::FeeTooLow { rate, min } =>
+                write!(f, "pays {} per kB, less than the minimum {}", rate, min),
+            &MempoolError::ReplacementFee { fee, required } =>
+                write!(f, "replacement pays {} in fees, less than the {} required to evict", fee, required),
+            &MempoolError::ReplacementFeeRate { rate, replaced } =>
+                write!(f, "replacement pays {} per kB, not more than the {} it conflicts with", rate, replaced),
+            &MempoolError::Invalid(ref e) => write!(f, "{}", e),
+            &MempoolError::Full => write!(f, "fee rate too low to fit in the pool"),
+        }
+    }
+}
+
+impl Error for MempoolError {
+    fn description(&self) -> &str {
+        "mempool error"
+    }
+}
+
+impl From<TxError> for MempoolError {
+    fn from(e: TxError) -> MempoolError {
+        MempoolError::Invalid(e)
+    }
+}
+
+/// The UTXO set as it would be were every pool transaction mined, except
+/// those `excluded`
+pub struct MempoolView<'a, V: UtxoView + 'a> {
+    pool: &'a Mempool,
+    base: &'a V,
+    height: u64,
+    excluded: HashSet<TxHash>,
+}
+
+impl<'a, V: UtxoView> UtxoView for MempoolView<'a, V> {
+    fn get(&self, outpoint: &OutPoint) -> Option<UtxoEntry> {
+        match self.pool.spent.get(outpoint) {
+            Some(spender) if !self.excluded.contains(spender) => return None,
+            _ => ()
+        }
+        match self.pool.entries.get(&outpoint.tx) {
+            Some(entry) if !self.excluded.contains(&outpoint.tx) => {
+                entry.tx.outputs().get(outpoint.index as usize).map(|output| {
+                    UtxoEntry { output: *output, height: self.height, coinbase: false }
+                })
+            },
+            _ => self.base.get(outpoint)
+        }
+    }
+}
+
+pub struct Mempool {
+    max_size: usize,
+    expiry: u64,
+    min_fee_rate: FeeRate,
+    entries: HashMap<TxHash, MempoolEntry>,
+    /// Pool transaction spending each outpoint
+    spent: HashMap<OutPoint, TxHash>,
+    /// Transactions by increasing fee rate, then arrival
+    by_fee_rate: BTreeMap<(FeeRate, u64), TxHash>,
+    /// Transactions by increasing fee rate of them and their descendants
+    /// together, then arrival
+    by_package_rate: BTreeMap<(FeeRate, u64), TxHash>,
+    /// Total encoded size of the transactions
+    size: usize,
+    next_sequence: u64,
+}
+
+impl Mempool {
+    /// Pool holding at most `max_size` bytes of transactions, each for at
+    /// most `expiry` seconds and paying at least `min_fee_rate`
+    pub fn new(max_size: usize, expiry: u64, min_fee_rate: FeeRate) -> Mempool {
+        Mempool {
+            max_size: max_size,
+            expiry: expiry,
+            min_fee_rate: min_fee_rate,
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            by_fee_rate: BTreeMap::new(),
+            by_package_rate: BTreeMap::new(),
+            size: 0,
+            next_sequence: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Total encoded size of the transactions, in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, hash: &TxHash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &TxHash) -> Option<&Transaction> {
+        self.entries.get(hash).map(|entry| &entry.tx)
+    }
+
+    pub fn entry(&self, hash: &TxHash) -> Option<&MempoolEntry> {
+        self.entries.get(hash)
+    }
+
+    /// Entries paying the most per byte first
+    pub fn by_fee_rate(&self) -> Vec<&MempoolEntry> {
+        self.by_fee_rate.values().rev().map(|hash| &self.entries[hash]).collect()
+    }
+
+    /// `utxos` with the pool transactions applied, as seen by a block at `height`
+    pub fn view<'a, V: UtxoView>(&'a self, utxos: &'a V, height: u64) -> MempoolView<'a, V> {
+        MempoolView { pool: self, base: utxos, height: height, excluded: HashSet::new() }
+    }
+
+    /// Least fee a replacement of `hash` no larger than it must pay, paying
+    /// at least `rate` too; `None` if the transaction is not in the pool
+    pub fn replacement_fee(&self, hash: &TxHash, rate: FeeRate) -> Option<u64> {
+        let entry = self.entries.get(hash)?;
+        let replaced = self.descendants(hash).iter()
+            .fold(0u64, |sum, h| sum.saturating_add(self.entries[h].fee));
+        Some(cmp::max(self.required_fee(replaced, entry.size), fee_at(rate, entry.size)))
+    }
+
+    /// Least fee a replacement of `size` bytes evicting `replaced` in fees
+    /// must pay: more than those, and the minimum rate for its own size
+    fn required_fee(&self, replaced: u64, size: usize) -> u64 {
+        replaced.saturating_add(cmp::max(fee_at(self.min_fee_rate, size), 1))
+    }
+
+    /// Add a transaction, were it included in a block at `height` on top of
+    /// `utxos`; `now` is the current Unix time. Makes room by evicting
+    /// cheaper transactions if needed, and replaces conflicting transactions
+    /// if it pays enough.
+    pub fn add<V: UtxoView>(&mut self, tx: Transaction, utxos: &V, height: u64, params: &ChainParams, now: u64) -> Result<TxHash, MempoolError> {
+        let hash = tx.hash();
+        if self.entries.contains_key(&hash) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+
+        // Pool transactions spending the same outputs go, along with their
+        // descendants
+        let mut conflicts = Vec::new();
+        let mut replaced = HashSet::new();
+        for input in tx.inputs() {
+            let outpoint = OutPoint { tx: input.tx, index: input.index };
+            if let Some(&spender) = self.spent.get(&outpoint) {
+                if !replaced.contains(&spender) {
+                    conflicts.push(spender);
+                    replaced.extend(self.descendants(&spender));
+                }
+            }
+        }
+        if replaced.len() > MAX_REPLACED {
+            return Err(MempoolError::TooManyReplaced(replaced.len()));
+        }
+
+        // Replaced transactions don't count, a replacement can't spend them
+        let fee = {
+            let view = MempoolView { pool: self, base: utxos, height: height, excluded: replaced };
+            let fee = validation::check_tx_inputs(&tx, &view, height, params)?;
+            replaced = view.excluded;
+            fee
+        };
+        let size = encoded_size(&tx);
+        let rate = fee_rate(fee, size);
+        if rate < self.min_fee_rate {
+            return Err(MempoolError::FeeTooLow { rate: rate, min: self.min_fee_rate });
+        }
+        if !replaced.is_empty() {
+            let replaced_fee = replaced.iter().fold(0u64, |sum, h| sum.saturating_add(self.entries[h].fee));
+            let required = self.required_fee(replaced_fee, size);
+            if fee < required {
+                return Err(MempoolError::ReplacementFee { fee: fee, required: required });
+            }
+            for conflict in &conflicts {
+                let conflict_rate = self.entries[conflict].fee_rate();
+                if rate <= conflict_rate {
+                    return Err(MempoolError::ReplacementFeeRate { rate: rate, replaced: conflict_rate });
+                }
+            }
+            let replaced: Vec<TxHash> = replaced.into_iter().collect();
+            self.remove_all(&replaced);
+        }
+
+        self.insert(MempoolEntry { tx: tx, fee: fee, size: size, time: now, sequence: 0, package_fee: 0, package_size: 0 });
+        self.trim();
+        if !self.entries.contains_key(&hash) {
+            return Err(MempoolError::Full);
+        }
+        Ok(hash)
+    }
+
+    /// Add an entry whose parents, if in the pool, are in already, and its
+    /// children not yet
+    fn insert(&mut self, mut entry: MempoolEntry) {
+        let hash = entry.tx.hash();
+        entry.sequence = self.next_sequence;
+        self.next_sequence += 1;
+        entry.package_fee = entry.fee;
+        entry.package_size = entry.size;
+        for input in entry.tx.inputs() {
+            self.spent.insert(OutPoint { tx: input.tx, index: input.index }, hash);
+        }
+        self.by_fee_rate.insert((entry.fee_rate(), entry.sequence), hash);
+        self.by_package_rate.insert(entry.package_key(), hash);
+        self.size += entry.size;
+        let (fee, size) = (entry.fee, entry.size);
+        self.entries.insert(hash, entry);
+
+        for ancestor in self.ancestors(&hash) {
+            self.update_package(&ancestor, |package_fee, package_size| {
+                (package_fee.saturating_add(fee), package_size + size)
+            });
+        }
+    }
+
+    /// Take a single transaction out, leaving its descendants in
+    fn remove(&mut self, hash: &TxHash) -> Option<MempoolEntry> {
+        let (fee, size) = match self.entries.get(hash) {
+            Some(entry) => (entry.fee, entry.size),
+            None => return None
+        };
+        for ancestor in self.ancestors(hash) {
+            self.update_package(&ancestor, |package_fee, package_size| {
+                (package_fee.saturating_sub(fee), package_size - size)
+            });
+        }
+
+        let entry = self.entries.remove(hash)?;
+        for input in entry.tx.inputs() {
+            self.spent.remove(&OutPoint { tx: input.tx, index: input.index });
+        }
+        self.by_fee_rate.remove(&(entry.fee_rate(), entry.sequence));
+        self.by_package_rate.remove(&entry.package_key());
+        self.size -= entry.size;
+        Some(entry)
+    }
+
+    /// Take transactions out, children before their parents, so that the
+    /// package totals of the ones left stay right
+    fn remove_all(&mut self, hashes: &[TxHash]) {
+        let mut hashes: Vec<(u64, TxHash)> = hashes.iter()
+            .filter_map(|h| self.entries.get(h).map(|entry| (entry.sequence, *h)))
+            .collect();
+        hashes.sort_by_key(|&(sequence, _)| cmp::Reverse(sequence));
+        for (_, hash) in hashes {
+            self.remove(&hash);
+        }
+    }
+
+    /// Change the package totals of a transaction, given the current ones
+    fn update_package<F: Fn(u64, usize) -> (u64, usize)>(&mut self, hash: &TxHash, update: F) {
+        let entry = match self.entries.get_mut(hash) {
+            Some(entry) => entry,
+            None => return
+        };
+        self.by_package_rate.remove(&entry.package_key());
+        let (fee, size) = update(entry.package_fee, entry.package_size);
+        entry.package_fee = fee;
+        entry.package_size = size;
+        self.by_package_rate.insert(entry.package_key(), *hash);
+    }
+
+    /// The transaction and every pool transaction depending on it
+    pub fn descendants(&self, hash: &TxHash) -> Vec<TxHash> {
+        if !self.entries.contains_key(hash) {
+            return Vec::new();
+        }
+        let mut found = vec![*hash];
+        let mut seen: HashSet<TxHash> = found.iter().cloned().collect();
+        let mut i = 0;
+        while i < found.len() {
+            let outputs = self.entries[&found[i]].tx.outputs();
+            for (index, _) in (0..=u8::max_value()).zip(outputs) {
+                let outpoint = OutPoint { tx: found[i], index: index };
+                if let Some(&child) = self.spent.get(&outpoint) {
+                    if seen.insert(child) {
+                        found.push(child);
+                    }
+                }
+            }
+            i += 1;
+        }
+        found
+    }
+
+    /// Pool transactions the transaction spends from, directly or not,
+    /// parents first
+    pub fn ancestors(&self, hash: &TxHash) -> Vec<TxHash> {
+        let mut seen = HashSet::new();
+        let mut queue = vec![*hash];
+        while let Some(next) = queue.pop() {
+            let entry = match self.entries.get(&next) {
+                Some(entry) => entry,
+                None => continue
+            };
+            for input in entry.tx.inputs() {
+                if self.entries.contains_key(&input.tx) && seen.insert(input.tx) {
+                    queue.push(input.tx);
+                }
+            }
+        }
+        // Parents always get in before their children
+        let mut found: Vec<TxHash> = seen.into_iter().collect();
+        found.sort_by_key(|h| self.entries[h].sequence);
+        found
+    }
+
+    /// Take a transaction out along with its descendants; returns how many
+    /// transactions left
+    fn remove_with_descendants(&mut self, hash: &TxHash) -> usize {
+        if !self.entries.contains_key(hash) {
+            return 0;
+        }
+        let removed = self.descendants(hash);
+        self.remove_all(&removed);
+        removed.len()
+    }
+
+    /// Evict the cheapest packages until the pool fits its cap
+    fn trim(&mut self) {
+        while self.size > self.max_size {
+            // Packages are scored as a whole, so that a cheap parent with a
+            // well-paying child (or the other way around) is judged fairly
+            let cheapest = match self.by_package_rate.values().next() {
+                Some(hash) => *hash,
+                None => break
+            };
+            self.remove_with_descendants(&cheapest);
+        }
+    }
+
+    /// Drop transactions that waited longer than the expiry; returns how many
+    pub fn expire(&mut self, now: u64) -> usize {
+        let expiry = self.expiry;
+        let expired: Vec<TxHash> = self.entries.iter()
+            .filter(|&(_, entry)| now.saturating_sub(entry.time) >= expiry)
+            .map(|(hash, _)| *hash)
+            .collect();
+        expired.iter().map(|hash| self.remove_with_descendants(hash)).sum()
+    }
+
+    /// A block was connected: its transactions are confirmed, and those
+    /// spending the same outputs can never be
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in &block.txs {
+            if self.remove(&tx.hash()).is_some() {
+                continue;
+            }
+            for input in tx.inputs() {
+                let outpoint = OutPoint { tx: input.tx, index: input.index };
+                if let Some(&spender) = self.spent.get(&outpoint) {
+                    self.remove_with_descendants(&spender);
+                }
+            }
+        }
+    }
+
+    /// Empty the pool, returning the entries in arrival order
+    pub fn drain(&mut self) -> Vec<MempoolEntry> {
+        let mut entries: Vec<MempoolEntry> = self.entries.drain().map(|(_, entry)| entry).collect();
+        entries.sort_by_key(|entry| entry.sequence);
+        self.spent.clear();
+        self.by_fee_rate.clear();
+        self.by_package_rate.clear();
+        self.size = 0;
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::BlockHash;
+    use test_util::{funded, spend};
+
+    #[test]
+    fn accept() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[100, 100]);
+        let mut pool = Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, 0);
+
+        // Chains of unconfirmed transactions
+        let parent = spend(1, &[(funding, 0, 100)], &[90]);
+        let child = spend(1, &[(parent.hash(), 0, 90)], &[70]);
+        assert_eq!(pool.add(parent.clone(), &set, 2, &params, 0), Ok(parent.hash()));
+        assert_eq!(pool.add(child.clone(), &set, 2, &params, 0), Ok(child.hash()));
+        assert_eq!(pool.entry(&child.hash()).unwrap().fee, 20);
+        assert_eq!(pool.size(), encoded_size(&parent) + encoded_size(&child));
+
+        assert_eq!(pool.add(parent.clone(), &set, 2, &params, 0), Err(MempoolError::AlreadyKnown));
+        assert_eq!(pool.add(spend(1, &[(funding, 0, 100)], &[80]), &set, 2, &params, 0),
+                   Err(MempoolError::ReplacementFee { fee: 20, required: 31 }));
+        let missing = OutPoint { tx: funding, index: 2 };
+        assert_eq!(pool.add(spend(1, &[(funding, 2, 0)], &[1]), &set, 2, &params, 0),
+                   Err(MempoolError::Invalid(TxError::MissingInput(missing))));
+
+        // Best paying first
+        let rich = spend(1, &[(funding, 1, 100)], &[10]);
+        pool.add(rich.clone(), &set, 2, &params, 0).unwrap();
+        let order: Vec<TxHash> = pool.by_fee_rate().iter().map(|e| e.tx.hash()).collect();
+        assert_eq!(order, vec![rich.hash(), child.hash(), parent.hash()]);
+    }
+
+    #[test]
+    fn eviction() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[1000, 1000, 1000]);
+        let cheap = spend(1, &[(funding, 0, 1000)], &[999]);
+        let child = spend(1, &[(cheap.hash(), 0, 999)], &[996]);
+        let rich = spend(1, &[(funding, 1, 1000)], &[500]);
+        let cap = encoded_size(&cheap) + encoded_size(&child) + encoded_size(&rich);
+        let mut pool = Mempool::new(cap, DEFAULT_EXPIRY, 0);
+        for tx in &[&cheap, &child, &rich] {
+            pool.add((*tx).clone(), &set, 2, &params, 0).unwrap();
+        }
+        let package = pool.entry(&cheap.hash()).unwrap();
+        assert_eq!((package.package_fee, package.package_size), (4, cap - encoded_size(&rich)));
+
+        // The cheapest package goes as a whole
+        let better = spend(1, &[(funding, 2, 1000)], &[900]);
+        pool.add(better.clone(), &set, 2, &params, 0).unwrap();
+        assert!(!pool.contains(&cheap.hash()) && !pool.contains(&child.hash()));
+        assert!(pool.contains(&rich.hash()) && pool.contains(&better.hash()));
+        assert!(pool.size() <= cap);
+
+        // A newcomer paying less than everything else is turned away
+        let (set, funding) = funded(1, &[1000, 1000]);
+        let paying = spend(1, &[(funding, 0, 1000)], &[500]);
+        let mut full = Mempool::new(encoded_size(&paying), DEFAULT_EXPIRY, 0);
+        full.add(paying.clone(), &set, 2, &params, 0).unwrap();
+        assert_eq!(full.add(spend(1, &[(funding, 1, 1000)], &[1000]), &set, 2, &params, 0), Err(MempoolError::Full));
+        assert_eq!(full.len(), 1);
+        assert!(full.contains(&paying.hash()));
+    }
+
+    #[test]
+    fn replacement() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[100, 100]);
+        let mut pool = Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, 0);
+        let stuck = spend(1, &[(funding, 0, 100)], &[10]);
+        let child = spend(1, &[(stuck.hash(), 0, 10)], &[5]);
+        pool.add(stuck.clone(), &set, 2, &params, 0).unwrap();
+        pool.add(child.clone(), &set, 2, &params, 0).unwrap();
+
+        // Paying more in total, but less per byte
+        let wide = spend(1, &[(funding, 0, 100), (funding, 1, 100)], &[104]);
+        match pool.add(wide, &set, 2, &params, 0) {
+            Err(MempoolError::ReplacementFeeRate { .. }) => (),
+            r => panic!("expected ReplacementFeeRate, got {:?}", r)
+        }
+        // Spending what it would evict
+        let greedy = spend(1, &[(funding, 0, 100), (stuck.hash(), 0, 10)], &[1]);
+        assert_eq!(pool.add(greedy, &set, 2, &params, 0),
+                   Err(MempoolError::Invalid(TxError::MissingInput(OutPoint { tx: stuck.hash(), index: 0 }))));
+
+        // The replacement takes the descendants out too
+        let required = pool.replacement_fee(&stuck.hash(), 0).unwrap();
+        assert_eq!(required, 96);
+        let bumped = spend(1, &[(funding, 0, 100)], &[100 - required]);
+        assert_eq!(pool.add(bumped.clone(), &set, 2, &params, 0), Ok(bumped.hash()));
+        assert!(!pool.contains(&stuck.hash()) && !pool.contains(&child.hash()));
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.size(), encoded_size(&bumped));
+
+        // Long chains can't be replaced at once
+        let (mut last, mut amount) = (bumped.hash(), 100 - required);
+        for _ in 0..MAX_REPLACED {
+            let next = spend(1, &[(last, 0, amount)], &[1]);
+            last = pool.add(next, &set, 2, &params, 0).unwrap();
+            amount = 1;
+        }
+        assert_eq!(pool.add(spend(1, &[(funding, 0, 100)], &[0]), &set, 2, &params, 0),
+                   Err(MempoolError::TooManyReplaced(MAX_REPLACED + 1)));
+        assert_eq!(pool.entry(&bumped.hash()).unwrap().package_size, pool.size());
+        let tail = pool.entry(&last).unwrap();
+        assert_eq!((tail.package_fee, tail.package_size), (tail.fee, tail.size));
+    }
+
+    #[test]
+    fn relay_fee() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[100_000]);
+        let mut pool = Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, DEFAULT_MIN_FEE_RATE);
+
+        // Free transactions are turned away
+        let free = spend(1, &[(funding, 0, 100_000)], &[100_000]);
+        assert_eq!(pool.add(free, &set, 2, &params, 0), Err(MempoolError::FeeTooLow { rate: 0, min: DEFAULT_MIN_FEE_RATE }));
+        let paying = spend(1, &[(funding, 0, 100_000)], &[99_000]);
+        pool.add(paying.clone(), &set, 2, &params, 0).unwrap();
+
+        // Replacements pay for their own size on top of what they evict
+        let cheap = spend(1, &[(funding, 0, 100_000)], &[98_999]);
+        let required = 1000 + fee_at(DEFAULT_MIN_FEE_RATE, encoded_size(&cheap));
+        assert_eq!(pool.add(cheap, &set, 2, &params, 0), Err(MempoolError::ReplacementFee { fee: 1001, required: required }));
+        assert_eq!(pool.replacement_fee(&paying.hash(), 0), Some(required));
+        let bumped = spend(1, &[(funding, 0, 100_000)], &[100_000 - required]);
+        assert_eq!(pool.add(bumped.clone(), &set, 2, &params, 0), Ok(bumped.hash()));
+    }
+
+    #[test]
+    fn removal() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[100, 100, 100]);
+        let mut pool = Mempool::new(DEFAULT_MAX_SIZE, 60, 0);
+        let old = spend(1, &[(funding, 0, 100)], &[90]);
+        let old_child = spend(1, &[(old.hash(), 0, 90)], &[80]);
+        let confirmed = spend(1, &[(funding, 1, 100)], &[90]);
+        let child = spend(1, &[(confirmed.hash(), 0, 90)], &[80]);
+        let conflicting = spend(1, &[(funding, 2, 100)], &[90]);
+        pool.add(old.clone(), &set, 2, &params, 0).unwrap();
+        pool.add(old_child.clone(), &set, 2, &params, 50).unwrap();
+        for tx in &[&confirmed, &child, &conflicting] {
+            pool.add((*tx).clone(), &set, 2, &params, 100).unwrap();
+        }
+
+        // Descendants go along with expired transactions
+        assert_eq!(pool.expire(100), 2);
+        assert!(!pool.contains(&old_child.hash()));
+
+        // Confirmed transactions leave, their children stay; double spends go
+        let double = spend(1, &[(funding, 2, 100)], &[10]);
+        let block = Block::new(BlockHash::new(), 0, 0, vec![confirmed.clone(), double]);
+        pool.remove_for_block(&block);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&child.hash()));
+        assert_eq!(pool.size(), encoded_size(&child));
+
+        let drained = pool.drain();
+        assert_eq!(drained.len(), 1);
+        assert_eq!((pool.len(), pool.size()), (0, 0));
+    }
+}
@@ -1,11 +1,73 @@
+use base58;
+use mempool::FeeRate;
+use protocol::{TxHash, HASH_LENGTH};
+use task::Task;
 
 pub trait Method: Send + Sync {
     fn name(&self) -> &'static str;
+    /// Node task carrying out the method; `None` if `params` are malformed
+    fn task(&self, params: &[String]) -> Option<Task>;
 }
 
 pub struct DumpPrivKey;
 
 impl Method for DumpPrivKey {
     fn name(&self) -> &'static str { "dumpprivkey" }
+    fn task(&self, _: &[String]) -> Option<Task> { Some(Task::DumpPrivKey) }
 }
 
+pub struct GetNetworkInfo;
+
+impl Method for GetNetworkInfo {
+    fn name(&self) -> &'static str { "getnetworkinfo" }
+    fn task(&self, _: &[String]) -> Option<Task> { Some(Task::GetNetworkInfo) }
+}
+
+pub struct GetPeerInfo;
+
+impl Method for GetPeerInfo {
+    fn name(&self) -> &'static str { "getpeerinfo" }
+    fn task(&self, _: &[String]) -> Option<Task> { Some(Task::GetPeerInfo) }
+}
+
+pub struct StartMining;
+
+impl Method for StartMining {
+    fn name(&self) -> &'static str { "startmining" }
+    fn task(&self, _: &[String]) -> Option<Task> { Some(Task::StartMining) }
+}
+
+pub struct StopMining;
+
+impl Method for StopMining {
+    fn name(&self) -> &'static str { "stopmining" }
+    fn task(&self, _: &[String]) -> Option<Task> { Some(Task::StopMining) }
+}
+
+pub struct GetSyncInfo;
+
+impl Method for GetSyncInfo {
+    fn name(&self) -> &'static str { "getsyncinfo" }
+    fn task(&self, _: &[String]) -> Option<Task> { Some(Task::GetSyncInfo) }
+}
+
+pub struct GetBlockTemplate;
+
+impl Method for GetBlockTemplate {
+    fn name(&self) -> &'static str { "getblocktemplate" }
+    fn task(&self, _: &[String]) -> Option<Task> { Some(Task::GetBlockTemplate) }
+}
+
+pub struct BumpFee;
+
+impl Method for BumpFee {
+    fn name(&self) -> &'static str { "bumpfee" }
+    fn task(&self, params: &[String]) -> Option<Task> {
+        if params.len() != 2 {
+            return None;
+        }
+        let tx = base58::decode(params[0].as_str()).ok().filter(|b| b.len() == HASH_LENGTH)?;
+        let fee_rate = params[1].parse::<FeeRate>().ok()?;
+        Some(Task::BumpFee { tx: TxHash::from_bytes(&tx), fee_rate: fee_rate })
+    }
+}
@@ -0,0 +1,184 @@
+// Proof-of-work miner.
+//
+// Mining a block template spawns one worker thread per CPU. Workers share the
+// nonce space by striding through it: worker `i` of `n` tries nonces `i`,
+// `i + n`, `i + 2n`... Once a round of nonces is used up, the coinbase's extra
+// nonce is bumped, which changes the merkle root, and the search starts over;
+// templates without a coinbase roll the timestamp instead. Solved blocks are
+// handed back to the daemon through a channel.
+//
+// Every job has its own stop flag, so a job can be cancelled (e.g. when a new
+// tip arrives) without waiting for its workers to notice.
+//
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use num_cpus;
+use pow;
+use protocol;
+use protocol::{Block, BlockHeader};
+
+/// Nonces a worker goes through before bumping the coinbase's extra nonce,
+/// or rolling the timestamp of templates without a coinbase
+pub const ROUND_NONCES: u64 = 1 << 32;
+
+/// How many hashes a worker tries between checks of its stop flag
+const STOP_CHECK_INTERVAL: u64 = 1 << 12;
+
+pub struct Miner {
+    threads: usize,
+    /// Stop flag of the running job, if any
+    job: Option<Arc<AtomicBool>>,
+    found_tx: Sender<Block>,
+    found_rx: Receiver<Block>,
+}
+
+impl Miner {
+    /// Create an idle miner; `threads` of 0 means one per CPU
+    pub fn new(threads: usize) -> Miner {
+        let (found_tx, found_rx) = channel();
+        Miner {
+            threads: if threads == 0 { num_cpus::get() } else { threads },
+            job: None,
+            found_tx: found_tx,
+            found_rx: found_rx,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.job.is_some()
+    }
+
+    /// Start mining on a template, cancelling the current job
+    pub fn start(&mut self, template: Block) {
+        self.stop();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let step = self.threads as u64;
+        for i in 0..self.threads {
+            let block = template.clone();
+            let stop = stop.clone();
+            let found = self.found_tx.clone();
+            thread::Builder::new()
+                .name(format!("miner-{}", i))
+                .spawn(move || work(block, i as u64, step, stop, found))
+                .unwrap();
+        }
+        self.job = Some(stop);
+    }
+
+    /// Cancel the current job, if any
+    pub fn stop(&mut self) {
+        if let Some(stop) = self.job.take() {
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Next solved block, if any
+    pub fn try_recv(&self) -> Option<Block> {
+        self.found_rx.try_recv().ok()
+    }
+}
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Search nonces `first`, `first + step`... below `ROUND_NONCES` for a valid
+/// proof-of-work; gives up early once `stop` is set
+pub fn search(header: &BlockHeader, first: u64, step: u64, stop: &AtomicBool) -> Option<u64> {
+    let target = pow::target_from_bits(header.bits)?;
+
+    let mut header = header.clone();
+    let mut nonce = first;
+    let mut tries = 0;
+    while nonce < ROUND_NONCES {
+        header.nonce = nonce;
+        if header.hash().as_bytes() <= &target {
+            return Some(nonce);
+        }
+
+        tries += 1;
+        if tries % STOP_CHECK_INTERVAL == 0 && stop.load(Ordering::Relaxed) {
+            return None;
+        }
+        nonce += step;
+    }
+    None
+}
+
+fn work(mut block: Block, first: u64, step: u64, stop: Arc<AtomicBool>, found: Sender<Block>) {
+    if pow::target_from_bits(block.header.bits).is_none() {
+        println!("Not mining: invalid difficulty target {:08x}", block.header.bits);
+        return;
+    }
+
+    while !stop.load(Ordering::Relaxed) {
+        if let Some(nonce) = search(&block.header, first, step, &stop) {
+            // Only the first worker to get here reports the block
+            if !stop.swap(true, Ordering::SeqCst) {
+                block.header.nonce = nonce;
+                let _ = found.send(block);
+            }
+            return;
+        }
+
+        // Round exhausted; every worker rolls the block the same way, so they
+        // keep searching disjoint nonces
+        let extra_nonce = block.txs.first()
+            .and_then(|tx| tx.coinbase_data())
+            .map(|data| data.extra_nonce.wrapping_add(1));
+        match extra_nonce {
+            Some(extra_nonce) => {
+                block.txs[0].set_extra_nonce(extra_nonce);
+                block.header.merkle_root = block.compute_merkle_root();
+            },
+            None => {
+                block.header.timestamp = cmp::max(block.header.timestamp + 1, protocol::unix_time());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use protocol::BlockHash;
+
+    #[test]
+    fn regtest() {
+        let template = Block::new(BlockHash::new(), protocol::unix_time(), pow::REGTEST_BITS, Vec::new());
+        let mut miner = Miner::new(2);
+        miner.start(template.clone());
+
+        let started = Instant::now();
+        let block = loop {
+            if let Some(block) = miner.try_recv() {
+                break block;
+            }
+            assert!(started.elapsed() < Duration::from_secs(10), "no block mined");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(pow::check_proof(&block.hash(), block.header.bits));
+        assert_eq!(block.header.prev_hash, template.header.prev_hash);
+        assert_eq!(block.header.merkle_root, template.header.merkle_root);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut header = Block::new(BlockHash::new(), 0, 0x03000001, Vec::new()).header;
+        let stop = AtomicBool::new(true);
+        assert_eq!(search(&header, 0, 1, &stop), None);
+        header.bits = 0x04923456;
+        assert_eq!(search(&header, 0, 1, &AtomicBool::new(false)), None);
+    }
+}
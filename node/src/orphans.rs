@@ -0,0 +1,166 @@
+// Orphans.
+//
+// Gossip doesn't preserve order: a transaction may reach us before the one
+// it spends, and a block before its parent. Such orphans wait here, keyed by
+// the parents they miss, until those arrive and they can be processed again.
+// The pools are bounded: orphans expire after a while, and when a pool is
+// full a random one makes room, so a peer can't choose what gets evicted.
+//
+// Orphan transactions are identified by wtxid rather than txid: a copy with
+// a corrupted signature shares the genuine transaction's txid, and must not
+// keep it out of the pool.
+//
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+use rand;
+use rand::Rng;
+use peer::PeerId;
+
+/// Most orphan transactions kept
+pub const MAX_ORPHAN_TXS: usize = 100;
+
+/// Most orphan blocks kept; they are much larger than transactions
+pub const MAX_ORPHAN_BLOCKS: usize = 32;
+
+/// How long an orphan waits for its parents
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+struct Orphan<H, T> {
+    item: T,
+    /// Missing parents
+    parents: Vec<H>,
+    /// Who sent the orphan
+    peer: PeerId,
+    received: Instant,
+}
+
+/// Orphans of type `T`, identified by hashes of type `H`, like their parents
+pub struct OrphanPool<H, T> {
+    max: usize,
+    expiry: Duration,
+    orphans: HashMap<H, Orphan<H, T>>,
+    /// Orphans waiting on each missing parent
+    by_parent: HashMap<H, Vec<H>>,
+}
+
+impl<H: Hash + Eq + Copy, T> OrphanPool<H, T> {
+    pub fn new(max: usize, expiry: Duration) -> OrphanPool<H, T> {
+        OrphanPool {
+            max: max,
+            expiry: expiry,
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn contains(&self, hash: &H) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Keep an orphan sent by `peer` until its `parents` arrive; false if
+    /// it is already there
+    pub fn add(&mut self, hash: H, item: T, parents: Vec<H>, peer: PeerId) -> bool {
+        if self.max == 0 || self.orphans.contains_key(&hash) {
+            return false;
+        }
+        self.expire();
+        while self.orphans.len() >= self.max {
+            let victim = rand::thread_rng().gen_range(0, self.orphans.len());
+            let victim = *self.orphans.keys().nth(victim).unwrap();
+            self.remove(&victim);
+        }
+
+        for parent in &parents {
+            self.by_parent.entry(*parent).or_default().push(hash);
+        }
+        self.orphans.insert(hash, Orphan {
+            item: item,
+            parents: parents,
+            peer: peer,
+            received: Instant::now(),
+        });
+        true
+    }
+
+    fn remove(&mut self, hash: &H) -> Option<Orphan<H, T>> {
+        let orphan = self.orphans.remove(hash)?;
+        for parent in &orphan.parents {
+            let empty = match self.by_parent.get_mut(parent) {
+                Some(waiting) => {
+                    waiting.retain(|h| h != hash);
+                    waiting.is_empty()
+                },
+                None => false
+            };
+            if empty {
+                self.by_parent.remove(parent);
+            }
+        }
+        Some(orphan)
+    }
+
+    /// A parent arrived: take out the orphans waiting on it, along with who
+    /// sent them. Those still missing other parents should be added back.
+    pub fn take_children(&mut self, parent: &H) -> Vec<(T, PeerId)> {
+        let waiting = self.by_parent.remove(parent).unwrap_or_default();
+        waiting.iter()
+            .filter_map(|hash| self.remove(hash))
+            .map(|orphan| (orphan.item, orphan.peer))
+            .collect()
+    }
+
+    /// Drop orphans that waited too long; returns how many
+    pub fn expire(&mut self) -> usize {
+        let expiry = self.expiry;
+        let expired: Vec<H> = self.orphans.iter()
+            .filter(|&(_, orphan)| orphan.received.elapsed() >= expiry)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in &expired {
+            self.remove(hash);
+        }
+        expired.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orphans() {
+        let mut pool: OrphanPool<u32, &str> = OrphanPool::new(3, ORPHAN_EXPIRY);
+        assert!(pool.add(10, "a", vec![1], 7));
+        assert!(pool.add(11, "b", vec![1, 2], 8));
+        assert!(!pool.add(11, "b", vec![1, 2], 8));
+        assert_eq!(pool.take_children(&3), vec![]);
+
+        // Children come out once, even if they wait on other parents too
+        let mut children = pool.take_children(&1);
+        children.sort();
+        assert_eq!(children, vec![("a", 7), ("b", 8)]);
+        assert_eq!(pool.take_children(&2), vec![]);
+        assert_eq!(pool.len(), 0);
+
+        // Full pools make room
+        for i in 0..10 {
+            assert!(pool.add(i, "c", vec![100 + i], 1));
+            assert!(pool.contains(&i));
+            assert!(pool.len() <= 3);
+        }
+        let left: usize = (0..10).map(|i| pool.take_children(&(100 + i)).len()).sum();
+        assert_eq!(left, 3);
+        assert!(pool.by_parent.is_empty());
+
+        // Orphans expire
+        let mut pool: OrphanPool<u32, &str> = OrphanPool::new(3, Duration::from_secs(0));
+        pool.add(1, "a", vec![2], 1);
+        assert_eq!(pool.expire(), 1);
+        assert_eq!(pool.len(), 0);
+    }
+}
@@ -0,0 +1,341 @@
+// Long-lived sessions with remote nodes.
+//
+// Every connection gets two threads: a reader that decodes messages off the
+// socket and forwards them to the node as `PeerEvent`s, and a writer that
+// drains a channel of outgoing messages. The rest of the node only ever talks
+// to a peer through its `PeerHandle`, so a slow peer never blocks anyone else.
+//
+// Before anything else, both sides exchange `version` and `verack` messages.
+// Only once that handshake completes does the node hear about the peer; a peer
+// that doesn't complete it in time, however slowly it trickles bytes in, is
+// dropped.
+//
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use chainparams;
+use protocol;
+use protocol::{MsgVersion, MsgVerack};
+use protocol::codec;
+use protocol::codec::{CodecError, Magic, Message};
+
+/// How long a peer has to complete the handshake, all messages included
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Identifier of a peer connection, unique during the node's lifetime
+pub type PeerId = u64;
+
+/// What we learned about a peer during the handshake
+#[derive(Debug, Clone)]
+pub struct PeerVersion {
+    /// Highest protocol version both sides speak
+    pub version: u32,
+    /// Services the peer offers, c.f. `protocol::SERVICE_*`
+    pub services: u64,
+    /// Height of the peer's best chain when it connected
+    pub height: u64,
+    pub user_agent: String,
+    /// Port the peer accepts connections on, 0 if it does not listen
+    pub port: u16,
+}
+
+/// Traffic counters of a session, updated by its reader and writer threads
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    pub bytes_recv: AtomicUsize,
+    pub bytes_sent: AtomicUsize,
+    /// Unix time of the last message received, 0 if none yet
+    pub last_recv: AtomicUsize,
+    /// Unix time of the last message sent, 0 if none yet
+    pub last_send: AtomicUsize,
+}
+
+/// What peer sessions report back to the node
+pub enum PeerEvent {
+    /// Session started; the handshake is under way
+    Opened(PeerHandle),
+    /// Could not connect to the given address
+    ConnectFailed(SocketAddr, String),
+    /// Handshake completed; messages from the peer will follow
+    Connected(PeerId, PeerVersion),
+    /// Peer sent a message
+    Message(PeerId, Message),
+    /// Session is over; no more events will follow for this peer
+    Disconnected(PeerId, String),
+}
+
+/// Write end of a peer session
+#[derive(Clone)]
+pub struct PeerHandle {
+    pub id: PeerId,
+    pub addr: SocketAddr,
+    /// Whether the remote node dialed us
+    pub inbound: bool,
+    /// Unix time the connection was opened
+    pub conntime: u64,
+    pub stats: Arc<PeerStats>,
+    outbox: Sender<Message>,
+    stream: Arc<TcpStream>,
+    version: Arc<AtomicUsize>,
+}
+
+impl PeerHandle {
+    /// Queue a message for the peer; returns false if the session is gone
+    pub fn send(&self, msg: Message) -> bool {
+        self.outbox.send(msg).is_ok()
+    }
+
+    /// Close the connection; the reader thread reports the disconnection
+    pub fn disconnect(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    /// Protocol version used to encode messages for this peer
+    pub fn version(&self) -> u32 {
+        self.version.load(Ordering::SeqCst) as u32
+    }
+}
+
+/// Start reader and writer threads for a freshly opened connection.
+///
+/// `local` is the version message we open the handshake with. Once both
+/// threads run, the new session is announced with `PeerEvent::Opened`
+/// before anything else; if either fails to start, it never is.
+/// Messages are framed with the selected network's magic string.
+pub fn spawn(id: PeerId, stream: TcpStream, inbound: bool, local: MsgVersion, events: Sender<PeerEvent>) -> io::Result<PeerHandle> {
+    let magic = chainparams::params().magic;
+    let addr = stream.peer_addr()?;
+    let (outbox, inbox) = channel();
+    let handle = PeerHandle {
+        id: id,
+        addr: addr,
+        inbound: inbound,
+        conntime: protocol::unix_time(),
+        stats: Arc::new(PeerStats::default()),
+        outbox: outbox,
+        stream: Arc::new(stream.try_clone()?),
+        version: Arc::new(AtomicUsize::new(codec::PROTOCOL_VERSION as usize)),
+    };
+
+    let wr = stream.try_clone()?;
+    let version = handle.version.clone();
+    let stats = handle.stats.clone();
+    thread::Builder::new()
+        .name(format!("peer-{}-write", id))
+        .spawn(move || write_loop(wr, magic, version, stats, inbox))?;
+
+    // The writer is already running; the reader announces the session as it
+    // starts, so no other event can come first
+    let peer = handle.clone();
+    thread::Builder::new()
+        .name(format!("peer-{}-read", id))
+        .spawn(move || {
+            let _ = events.send(PeerEvent::Opened(peer.clone()));
+            read_loop(peer, stream, magic, local, events)
+        })?;
+
+    Ok(handle)
+}
+
+/// Read the next message, no matter its type
+fn next_message<R: Read>(rd: &mut R, magic: &Magic) -> Result<Message, String> {
+    codec::decode(rd, magic)
+        .map(|(_, msg)| msg)
+        .map_err(|e| format!("{}", e))
+}
+
+/// Run our side of the version/verack exchange
+fn handshake<R: Read>(rd: &mut R, magic: &Magic, peer: &PeerHandle, local: MsgVersion) -> Result<PeerVersion, String> {
+    let nonce = local.nonce;
+    let genesis = local.genesis;
+    peer.send(Message::Version(local));
+
+    let remote = match next_message(rd, magic)? {
+        Message::Version(v) => v,
+        msg => return Err(format!("sent `{}` before `version`", msg.name()))
+    };
+    if remote.nonce == nonce {
+        return Err(String::from("connected to ourselves"));
+    }
+    if remote.genesis != genesis {
+        return Err(format!("on another chain, with genesis block {:?}", remote.genesis));
+    }
+
+    // Settle on the highest version both sides speak
+    let version = cmp::min(remote.version, codec::PROTOCOL_VERSION);
+    if version < codec::MIN_PROTOCOL_VERSION {
+        return Err(format!("protocol version {} is no longer supported", remote.version));
+    }
+    peer.version.store(version as usize, Ordering::SeqCst);
+    peer.send(Message::Verack(MsgVerack {}));
+
+    match next_message(rd, magic)? {
+        Message::Verack(_) => (),
+        msg => return Err(format!("sent `{}` before `verack`", msg.name()))
+    };
+
+    Ok(PeerVersion {
+        version: version,
+        services: remote.services,
+        height: remote.height,
+        user_agent: remote.user_agent,
+        port: remote.port,
+    })
+}
+
+fn read_loop(peer: PeerHandle, stream: TcpStream, magic: Magic, local: MsgVersion, events: Sender<PeerEvent>) {
+    let id = peer.id;
+    let mut rd = Counted { inner: BufReader::new(&stream), stats: peer.stats.clone() };
+
+    let handshake = {
+        let mut rd = Deadline { inner: &mut rd, stream: &stream, until: Instant::now() + HANDSHAKE_TIMEOUT };
+        handshake(&mut rd, &magic, &peer, local)
+    };
+    let _ = stream.set_read_timeout(None);
+
+    let reason = match handshake {
+        Ok(version) => {
+            if events.send(PeerEvent::Connected(id, version)).is_ok() {
+                session_loop(id, &mut rd, &magic, &events)
+            } else {
+                String::from("node is shutting down")
+            }
+        },
+        Err(reason) => format!("handshake failed: {}", reason)
+    };
+
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = events.send(PeerEvent::Disconnected(id, reason));
+}
+
+/// Forward messages to the node until the session ends; returns why it did
+fn session_loop<R: Read>(id: PeerId, rd: &mut Counted<R>, magic: &Magic, events: &Sender<PeerEvent>) -> String {
+    loop {
+        match codec::decode(rd, magic) {
+            Ok((_, msg)) => {
+                rd.stats.last_recv.store(protocol::unix_time() as usize, Ordering::SeqCst);
+                if events.send(PeerEvent::Message(id, msg)).is_err() {
+                    break String::from("node is shutting down");
+                }
+            },
+            // The stream is still in sync after these two; skip the message
+            Err(e @ CodecError::UnknownType(_)) | Err(e @ CodecError::BadChecksum { .. }) => {
+                println!("Peer {}: ignoring message: {}", id, e);
+            },
+            Err(e) => break format!("{}", e)
+        }
+    }
+}
+
+fn write_loop(stream: TcpStream, magic: Magic, version: Arc<AtomicUsize>, stats: Arc<PeerStats>, inbox: Receiver<Message>) {
+    let mut wr = Counted { inner: BufWriter::new(&stream), stats: stats };
+
+    // Ends when every handle is dropped or the socket is closed
+    for msg in inbox {
+        let res = codec::encode(&mut wr, &magic, version.load(Ordering::SeqCst) as u32, &msg)
+            .and_then(|_| wr.flush().map_err(CodecError::from));
+        if let Err(e) = res {
+            println!("Could not send `{}` message: {}", msg.name(), e);
+            break;
+        }
+        wr.stats.last_send.store(protocol::unix_time() as usize, Ordering::SeqCst);
+    }
+
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Reader failing once `until` has passed, however the bytes come in
+struct Deadline<'a, R> {
+    inner: R,
+    stream: &'a TcpStream,
+    until: Instant,
+}
+
+impl<'a, R: Read> Read for Deadline<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        if now >= self.until {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "took too long"));
+        }
+        self.stream.set_read_timeout(Some(self.until - now))?;
+        self.inner.read(buf)
+    }
+}
+
+/// Reader or writer keeping track of the bytes going through it
+struct Counted<T> {
+    inner: T,
+    stats: Arc<PeerStats>,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.stats.bytes_recv.fetch_add(n, Ordering::SeqCst);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.stats.bytes_sent.fetch_add(n, Ordering::SeqCst);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Peers that completed the handshake, shared by every part of the node that needs to talk to them
+#[derive(Clone)]
+pub struct PeerSet {
+    peers: Arc<Mutex<HashMap<PeerId, PeerHandle>>>,
+}
+
+impl PeerSet {
+    pub fn new() -> PeerSet {
+        PeerSet {
+            peers: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    pub fn insert(&self, peer: PeerHandle) {
+        self.peers.lock().unwrap().insert(peer.id, peer);
+    }
+
+    pub fn remove(&self, id: PeerId) -> Option<PeerHandle> {
+        self.peers.lock().unwrap().remove(&id)
+    }
+
+    pub fn get(&self, id: PeerId) -> Option<PeerHandle> {
+        self.peers.lock().unwrap().get(&id).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    /// Queue a message for a single peer
+    pub fn send(&self, id: PeerId, msg: Message) -> bool {
+        match self.get(id) {
+            Some(peer) => peer.send(msg),
+            None => false
+        }
+    }
+
+    /// Queue a message for every peer; `make` is called once per peer
+    pub fn broadcast<F: Fn() -> Message>(&self, make: F) {
+        for peer in self.peers.lock().unwrap().values() {
+            peer.send(make());
+        }
+    }
+}
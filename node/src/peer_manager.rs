@@ -0,0 +1,462 @@
+// Book-keeping of peer sessions.
+//
+// The `PeerManager` accepts inbound connections, keeps a target number of
+// outbound ones open to nodes from the address book, and records what we know
+// about every session. It also takes care of address gossip (`getaddr` and
+// `addr`) and of keepalive pings, which measure every peer's latency. It
+// lives on the node's main thread and is fed every `PeerEvent`; connecting
+// and accepting happen on their own threads, which report back through the
+// same event channel.
+//
+// Peers that misbehave, e.g. by sending invalid blocks, build up a score;
+// past `BAN_THRESHOLD` they are disconnected and their IP is banned for a
+// while, in both directions.
+//
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+use protocol;
+use rand;
+use protocol::{MsgVersion, MsgGetAddr, MsgAddr, MsgPing, MsgPong, Node, NetAddr, MAX_ADDR};
+use protocol::codec::Message;
+use addrbook::AddrBook;
+use peer;
+use peer::{PeerEvent, PeerHandle, PeerId, PeerSet, PeerVersion};
+
+/// Outbound connections we try to keep open
+pub const TARGET_OUTBOUND: usize = 8;
+
+/// Most inbound sessions open at once, handshake completed or not; further
+/// connections are refused
+pub const MAX_INBOUND: usize = 117;
+
+/// How long to wait for an outbound connection to open
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the address book is written to disk
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often peers are pinged
+pub const PING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a peer has to answer a ping before being disconnected
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Misbehavior score at which a peer gets banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// How long a ban lasts
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Per-peer state, as reported by `getpeerinfo`
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub id: PeerId,
+    pub addr: SocketAddr,
+    pub inbound: bool,
+    /// Unix time the connection was opened
+    pub conntime: u64,
+    pub bytes_recv: u64,
+    pub bytes_sent: u64,
+    /// Unix time of the last message received, 0 if none yet
+    pub last_recv: u64,
+    /// Unix time of the last message sent, 0 if none yet
+    pub last_send: u64,
+    /// Negotiated protocol version; `None` while the handshake is under way
+    pub version: Option<u32>,
+    pub services: u64,
+    pub user_agent: String,
+    /// Peer's best chain height when it connected
+    pub height: u64,
+    /// Round-trip time of the last answered ping, in milliseconds
+    pub latency: Option<u64>,
+    /// Lowest round-trip time seen so far, in milliseconds
+    pub min_latency: Option<u64>,
+    /// How long the outstanding ping has been waiting for an answer, in milliseconds
+    pub ping_wait: Option<u64>,
+    /// Misbehavior score, c.f. `BAN_THRESHOLD`
+    pub ban_score: u32,
+}
+
+/// A session and what the handshake told us about it
+struct Session {
+    handle: PeerHandle,
+    version: Option<PeerVersion>,
+    /// Nonce of the ping waiting for a pong, and when it was sent
+    ping: Option<(u64, Instant)>,
+    /// When the last ping was sent
+    last_ping: Option<Instant>,
+    latency: Option<Duration>,
+    min_latency: Option<Duration>,
+    ban_score: u32,
+}
+
+impl Session {
+    fn new(handle: PeerHandle) -> Session {
+        Session {
+            handle: handle,
+            version: None,
+            ping: None,
+            last_ping: None,
+            latency: None,
+            min_latency: None,
+            ban_score: 0,
+        }
+    }
+
+    /// Ping the peer if it is due; returns false if the last ping went unanswered for too long
+    fn keepalive(&mut self) -> bool {
+        if let Some((_, sent)) = self.ping {
+            return sent.elapsed() < PING_TIMEOUT;
+        }
+
+        let due = match self.last_ping {
+            Some(last) => last.elapsed() >= PING_INTERVAL,
+            None => true
+        };
+        if due {
+            let nonce = rand::random();
+            let now = Instant::now();
+            self.ping = Some((nonce, now));
+            self.last_ping = Some(now);
+            self.handle.send(Message::Ping(MsgPing { nonce: nonce }));
+        }
+        true
+    }
+
+    fn pong(&mut self, nonce: u64) {
+        match self.ping {
+            Some((expected, sent)) if expected == nonce => {
+                let rtt = sent.elapsed();
+                self.latency = Some(rtt);
+                self.min_latency = Some(match self.min_latency {
+                    Some(min) if min < rtt => min,
+                    _ => rtt
+                });
+                self.ping = None;
+            },
+            // Answer to a ping we gave up on, or a bogus one
+            _ => ()
+        }
+    }
+}
+
+/// Version message for a session opened now, advertising our current height
+fn local_version(local: &MsgVersion, height: &AtomicUsize) -> MsgVersion {
+    let mut version = local.clone();
+    version.height = height.load(Ordering::SeqCst) as u64;
+    version
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_millis() as u64
+}
+
+pub struct PeerManager {
+    /// Sessions that completed the handshake
+    peers: PeerSet,
+    /// Every open session, handshake completed or not
+    sessions: HashMap<PeerId, Session>,
+    book: AddrBook,
+    /// Outbound connection attempts under way and open outbound sessions
+    dialing: HashSet<SocketAddr>,
+    /// Open inbound sessions, counted as soon as they are accepted
+    inbound: Arc<AtomicUsize>,
+    /// Banned IPs, and when their ban ends
+    banned: HashMap<IpAddr, Instant>,
+    last_save: Instant,
+    target_outbound: usize,
+    next_id: Arc<AtomicUsize>,
+    local: MsgVersion,
+    /// Height of our best chain, advertised in every handshake
+    height: Arc<AtomicUsize>,
+    events: Sender<PeerEvent>,
+}
+
+impl PeerManager {
+    /// `local` is the version message sessions open with, but for the
+    /// height, c.f. `set_height`; every session reports to `events`
+    pub fn new(target_outbound: usize, local: MsgVersion, book: AddrBook, events: Sender<PeerEvent>) -> PeerManager {
+        PeerManager {
+            peers: PeerSet::new(),
+            sessions: HashMap::new(),
+            book: book,
+            dialing: HashSet::new(),
+            inbound: Arc::new(AtomicUsize::new(0)),
+            banned: HashMap::new(),
+            last_save: Instant::now(),
+            target_outbound: target_outbound,
+            next_id: Arc::new(AtomicUsize::new(0)),
+            height: Arc::new(AtomicUsize::new(local.height as usize)),
+            local: local,
+            events: events,
+        }
+    }
+
+    /// Our best chain moved; sessions opened from now on advertise `height`
+    pub fn set_height(&self, height: u64) {
+        self.height.store(height as usize, Ordering::SeqCst);
+    }
+
+    /// Peers that completed the handshake
+    pub fn peers(&self) -> &PeerSet {
+        &self.peers
+    }
+
+    /// Remember a node to open outbound connections to
+    pub fn add_known(&mut self, node: &Node) {
+        let now = protocol::unix_time();
+        self.book.add(&NetAddr { node: node.clone(), last_seen: now }, None, now);
+    }
+
+    /// Accept inbound connections on a separate thread, up to `MAX_INBOUND`
+    pub fn listen(&self, listener: TcpListener) {
+        let inbound = self.inbound.clone();
+        let next_id = self.next_id.clone();
+        let local = self.local.clone();
+        let height = self.height.clone();
+        let events = self.events.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => { println!("Could not accept connection: {}", e); continue; }
+                };
+                // Released when the session's `Disconnected` event is handled
+                if inbound.fetch_add(1, Ordering::SeqCst) >= MAX_INBOUND {
+                    inbound.fetch_sub(1, Ordering::SeqCst);
+                    println!("Too many inbound connections, refusing {:?}", stream.peer_addr());
+                    continue;
+                }
+
+                let id = next_id.fetch_add(1, Ordering::SeqCst) as PeerId;
+                if let Err(e) = peer::spawn(id, stream, true, local_version(&local, &height), events.clone()) {
+                    inbound.fetch_sub(1, Ordering::SeqCst);
+                    println!("Could not start peer session: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Open outbound connections until the target is met, ping peers and
+    /// save the address book
+    pub fn tick(&mut self) {
+        for session in self.sessions.values_mut() {
+            if session.version.is_some() && !session.keepalive() {
+                println!("Peer {} did not answer ping in time, disconnecting", session.handle.id);
+                session.handle.disconnect();
+            }
+        }
+
+        self.banned.retain(|_, until| *until > Instant::now());
+
+        let now = protocol::unix_time();
+        let wanted = self.target_outbound.saturating_sub(self.dialing.len());
+        let banned = &self.banned;
+        let candidates: Vec<SocketAddr> = self.book.candidates(now).into_iter()
+            .filter(|addr| !self.dialing.contains(addr) && !banned.contains_key(&addr.ip()))
+            .take(wanted)
+            .collect();
+
+        for addr in candidates {
+            self.book.attempt(&addr, now);
+            self.dialing.insert(addr);
+            self.connect(addr);
+        }
+
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            self.save();
+        }
+    }
+
+    /// Write the address book to disk
+    pub fn save(&mut self) {
+        if let Err(e) = self.book.save() {
+            println!("Could not save address book: {}", e);
+        }
+        self.last_save = Instant::now();
+    }
+
+    /// Whether connections from or to this IP are refused
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        match self.banned.get(ip) {
+            Some(until) => *until > Instant::now(),
+            None => false
+        }
+    }
+
+    /// Add to a peer's misbehavior score, banning it once the score reaches
+    /// `BAN_THRESHOLD`
+    pub fn misbehaving(&mut self, id: PeerId, score: u32, reason: &str) {
+        let session = match self.sessions.get_mut(&id) {
+            Some(session) => session,
+            None => return
+        };
+        if score == 0 {
+            return;
+        }
+        session.ban_score = session.ban_score.saturating_add(score);
+        println!("Peer {} misbehaving ({} -> {}): {}", id, score, session.ban_score, reason);
+        if session.ban_score >= BAN_THRESHOLD {
+            println!("Banning {} for {} seconds", session.handle.addr.ip(), BAN_DURATION.as_secs());
+            self.banned.insert(session.handle.addr.ip(), Instant::now() + BAN_DURATION);
+            session.handle.disconnect();
+        }
+    }
+
+    /// Dial a node on a separate thread
+    fn connect(&self, addr: SocketAddr) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) as PeerId;
+        let local = local_version(&self.local, &self.height);
+        let events = self.events.clone();
+        thread::spawn(move || {
+            let res = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+                .and_then(|stream| peer::spawn(id, stream, false, local, events.clone()));
+            if let Err(e) = res {
+                let _ = events.send(PeerEvent::ConnectFailed(addr, format!("{}", e)));
+            }
+        });
+    }
+
+    /// Update book-keeping with a session event. Connections, disconnections
+    /// and messages other than address gossip and keepalives are handed back
+    /// for the node to process.
+    pub fn handle_event(&mut self, event: PeerEvent) -> Option<PeerEvent> {
+        match event {
+            PeerEvent::Opened(handle) => {
+                println!("Opened {} connection {} with {}",
+                    if handle.inbound { "inbound" } else { "outbound" }, handle.id, handle.addr);
+                if self.is_banned(&handle.addr.ip()) {
+                    println!("Peer {} is banned, disconnecting", handle.id);
+                    handle.disconnect();
+                }
+                self.sessions.insert(handle.id, Session::new(handle));
+            },
+            PeerEvent::ConnectFailed(addr, reason) => {
+                println!("Could not connect to {}: {}", addr, reason);
+                self.dialing.remove(&addr);
+                self.book.failed(&addr);
+            },
+            PeerEvent::Connected(id, version) => {
+                if let Some(session) = self.sessions.get_mut(&id) {
+                    println!("Peer {} is `{}` at height {}, speaking protocol version {}",
+                        id, version.user_agent, version.height, version.version);
+                    let now = protocol::unix_time();
+                    let handle = &session.handle;
+                    if handle.inbound {
+                        // Remember where the peer accepts connections itself
+                        if version.port != 0 {
+                            let node = Node::from_socket_addr(&SocketAddr::new(handle.addr.ip(), version.port));
+                            self.book.add(&NetAddr { node: node, last_seen: now }, Some(handle.addr.ip()), now);
+                        }
+                    } else {
+                        self.book.good(&handle.addr, now);
+                        handle.send(Message::GetAddr(MsgGetAddr {}));
+                    }
+                    session.version = Some(version.clone());
+                    self.peers.insert(handle.clone());
+                    return Some(PeerEvent::Connected(id, version));
+                }
+            },
+            PeerEvent::Message(id, Message::GetAddr(_)) => {
+                let addrs = self.book.sample(MAX_ADDR);
+                self.peers.send(id, Message::Addr(MsgAddr { addrs: addrs }));
+            },
+            PeerEvent::Message(id, Message::Addr(msg)) => {
+                self.handle_addr(id, msg);
+            },
+            PeerEvent::Message(id, Message::Ping(ping)) => {
+                self.peers.send(id, Message::Pong(MsgPong { nonce: ping.nonce }));
+            },
+            PeerEvent::Message(id, Message::Pong(pong)) => {
+                if let Some(session) = self.sessions.get_mut(&id) {
+                    session.pong(pong.nonce);
+                }
+            },
+            PeerEvent::Message(id, msg) => {
+                return Some(PeerEvent::Message(id, msg));
+            },
+            PeerEvent::Disconnected(id, reason) => {
+                println!("Peer {} disconnected: {}", id, reason);
+                self.peers.remove(id);
+                if let Some(session) = self.sessions.remove(&id) {
+                    if session.handle.inbound {
+                        self.inbound.fetch_sub(1, Ordering::SeqCst);
+                    } else {
+                        self.dialing.remove(&session.handle.addr);
+
+                        // Sessions that never got past the handshake count as failures
+                        if session.version.is_none() {
+                            self.book.failed(&session.handle.addr);
+                        } else {
+                            let duration = protocol::unix_time().saturating_sub(session.handle.conntime);
+                            self.book.disconnected(&session.handle.addr, duration);
+                        }
+                    }
+                    if session.version.is_some() {
+                        return Some(PeerEvent::Disconnected(id, reason));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn handle_addr(&mut self, id: PeerId, msg: MsgAddr) {
+        let source = match self.sessions.get(&id) {
+            Some(session) => session.handle.addr.ip(),
+            None => return
+        };
+        if msg.addrs.len() > MAX_ADDR {
+            println!("Peer {} sent {} addresses at once, disconnecting", id, msg.addrs.len());
+            self.sessions[&id].handle.disconnect();
+            return;
+        }
+
+        let now = protocol::unix_time();
+        for addr in &msg.addrs {
+            self.book.add(addr, Some(source), now);
+        }
+    }
+
+    /// Current state of every session
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        let mut info: Vec<PeerInfo> = self.sessions.values().map(|s| {
+            let stats = &s.handle.stats;
+            PeerInfo {
+                id: s.handle.id,
+                addr: s.handle.addr,
+                inbound: s.handle.inbound,
+                conntime: s.handle.conntime,
+                bytes_recv: stats.bytes_recv.load(Ordering::SeqCst) as u64,
+                bytes_sent: stats.bytes_sent.load(Ordering::SeqCst) as u64,
+                last_recv: stats.last_recv.load(Ordering::SeqCst) as u64,
+                last_send: stats.last_send.load(Ordering::SeqCst) as u64,
+                version: s.version.as_ref().map(|v| v.version),
+                services: s.version.as_ref().map_or(0, |v| v.services),
+                user_agent: s.version.as_ref().map_or(String::new(), |v| v.user_agent.clone()),
+                height: s.version.as_ref().map_or(0, |v| v.height),
+                latency: s.latency.map(millis),
+                min_latency: s.min_latency.map(millis),
+                ping_wait: s.ping.map(|(_, sent)| millis(sent.elapsed())),
+                ban_score: s.ban_score,
+            }
+        }).collect();
+        info.sort_by_key(|i| i.id);
+        info
+    }
+
+    /// Peers that completed the handshake, fastest first; peers we have no
+    /// latency measurement for yet come last
+    pub fn peers_by_latency(&self) -> Vec<PeerId> {
+        let mut peers: Vec<&Session> = self.sessions.values()
+            .filter(|s| s.version.is_some())
+            .collect();
+        peers.sort_by_key(|s| (s.min_latency.is_none(), s.min_latency, s.handle.id));
+        peers.iter().map(|s| s.handle.id).collect()
+    }
+}
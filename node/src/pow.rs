@@ -0,0 +1,299 @@
+// Proof-of-work.
+//
+// A header's hash, read as a 256-bit big-endian number, must not exceed the
+// target encoded in its `bits` field. Targets use Bitcoin's compact form: the
+// top byte is a base-256 exponent (the target's length in bytes) and the lower
+// 23 bits are the mantissa. Bit 23 is a sign bit; negative targets are invalid.
+//
+use protocol::{BlockHash, HASH_LENGTH};
+
+/// Regression-test difficulty; about every other hash is a valid proof
+pub const REGTEST_BITS: u32 = 0x207fffff;
+
+/// Target as a big-endian 256-bit number
+pub type Target = [u8; HASH_LENGTH];
+
+/// Expand a compact target; `None` if it is negative, zero or overflows
+pub fn target_from_bits(bits: u32) -> Option<Target> {
+    if bits & 0x00800000 != 0 {
+        return None;
+    }
+
+    let exponent = (bits >> 24) as i32;
+    let mantissa = bits & 0x007fffff;
+    let mut target = [0; HASH_LENGTH];
+    for k in 0..3 {
+        let byte = (mantissa >> (8 * k)) as u8;
+        // Position of the byte, counting from the least significant one
+        let pos = exponent - 3 + k;
+        if pos < 0 {
+            continue;
+        }
+        if pos >= HASH_LENGTH as i32 {
+            if byte != 0 {
+                return None;
+            }
+            continue;
+        }
+        target[HASH_LENGTH - 1 - pos as usize] = byte;
+    }
+
+    if target.iter().all(|&b| b == 0) {
+        return None;
+    }
+    Some(target)
+}
+
+/// Compact form of a target, rounding down to 23 bits of precision
+pub fn bits_from_target(target: &Target) -> u32 {
+    let size = match target.iter().position(|&b| b != 0) {
+        Some(i) => HASH_LENGTH - i,
+        None => return 0
+    };
+
+    let start = HASH_LENGTH - size;
+    let mut mantissa: u32 = 0;
+    for k in 0..3 {
+        let byte = if start + k < HASH_LENGTH { target[start + k] } else { 0 };
+        mantissa = (mantissa << 8) | byte as u32;
+    }
+
+    // Keep the sign bit clear by moving to a larger exponent
+    let mut exponent = size as u32;
+    if mantissa & 0x00800000 != 0 {
+        mantissa >>= 8;
+        exponent += 1;
+    }
+    (exponent << 24) | mantissa
+}
+
+/// Unsigned 256-bit integer, enough for target arithmetic.
+///
+/// Limbs are stored most significant first, so the derived ordering is the
+/// numeric one.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub fn zero() -> U256 {
+        U256([0; 4])
+    }
+
+    pub fn from_u64(n: u64) -> U256 {
+        U256([0, 0, 0, n])
+    }
+
+    pub fn from_target(target: &Target) -> U256 {
+        let mut limbs = [0; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            for byte in &target[8 * i..8 * i + 8] {
+                *limb = (*limb << 8) | *byte as u64;
+            }
+        }
+        U256(limbs)
+    }
+
+    pub fn to_target(&self) -> Target {
+        let mut target = [0; HASH_LENGTH];
+        for (i, limb) in self.0.iter().enumerate() {
+            for k in 0..8 {
+                target[8 * i + k] = (limb >> (8 * (7 - k))) as u8;
+            }
+        }
+        target
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|&limb| limb == 0)
+    }
+
+    /// Sum; `None` on overflow
+    pub fn checked_add(&self, other: &U256) -> Option<U256> {
+        let mut limbs = [0; 4];
+        let mut carry = 0u128;
+        for i in (0..4).rev() {
+            let sum = self.0[i] as u128 + other.0[i] as u128 + carry;
+            limbs[i] = sum as u64;
+            carry = sum >> 64;
+        }
+        if carry != 0 { None } else { Some(U256(limbs)) }
+    }
+
+    /// Product; `None` on overflow
+    pub fn checked_mul_u64(&self, m: u64) -> Option<U256> {
+        let mut limbs = [0; 4];
+        let mut carry = 0u128;
+        for i in (0..4).rev() {
+            let product = self.0[i] as u128 * m as u128 + carry;
+            limbs[i] = product as u64;
+            carry = product >> 64;
+        }
+        if carry != 0 { None } else { Some(U256(limbs)) }
+    }
+
+    /// Quotient and remainder; panics if `d` is zero
+    pub fn div_rem_u64(&self, d: u64) -> (U256, u64) {
+        let mut limbs = [0; 4];
+        let mut rem = 0u128;
+        for (limb, &word) in limbs.iter_mut().zip(self.0.iter()) {
+            let cur = (rem << 64) | word as u128;
+            *limb = (cur / d as u128) as u64;
+            rem = cur % d as u128;
+        }
+        (U256(limbs), rem as u64)
+    }
+
+    /// Quotient; panics if `d` is zero
+    pub fn div(&self, d: &U256) -> U256 {
+        assert!(!d.is_zero(), "division by zero");
+
+        // Long division, one bit at a time
+        let mut quotient = U256::zero();
+        let mut rem = U256::zero();
+        for bit in 0..256 {
+            let overflow = rem.0[0] >> 63 != 0;
+            rem = rem.shl1();
+            rem.0[3] |= (self.0[bit / 64] >> (63 - bit % 64)) & 1;
+            quotient = quotient.shl1();
+            if overflow || rem >= *d {
+                rem = rem.wrapping_sub(d);
+                quotient.0[3] |= 1;
+            }
+        }
+        quotient
+    }
+
+    fn shl1(&self) -> U256 {
+        let mut limbs = [0; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = self.0[i] << 1 | if i < 3 { self.0[i + 1] >> 63 } else { 0 };
+        }
+        U256(limbs)
+    }
+
+    fn wrapping_sub(&self, other: &U256) -> U256 {
+        let mut limbs = [0; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            limbs[i] = diff;
+            borrow = b1 || b2;
+        }
+        U256(limbs)
+    }
+
+    /// Bitwise complement
+    pub fn not(&self) -> U256 {
+        U256([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+}
+
+/// Expected number of hashes needed to meet the target encoded in `bits`,
+/// i.e. `2^256 / (target + 1)`; zero if the target is invalid
+pub fn block_work(bits: u32) -> U256 {
+    let target = match target_from_bits(bits) {
+        Some(target) => U256::from_target(&target),
+        None => return U256::zero()
+    };
+    // 2^256 doesn't fit, but 2^256 / (t + 1) = (2^256 - t - 1) / (t + 1) + 1
+    match target.checked_add(&U256::from_u64(1)) {
+        Some(divisor) => target.not().div(&divisor).checked_add(&U256::from_u64(1)).unwrap(),
+        None => U256::from_u64(1)
+    }
+}
+
+/// Whether a hash satisfies the target encoded in `bits`
+pub fn check_proof(hash: &BlockHash, bits: u32) -> bool {
+    match target_from_bits(bits) {
+        Some(target) => hash.as_bytes() <= &target,
+        None => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Target {
+        let mut target = [0; HASH_LENGTH];
+        let padded = format!("{:0>64}", s);
+        for i in 0..HASH_LENGTH {
+            target[i] = u8::from_str_radix(&padded[2 * i..2 * i + 2], 16).unwrap();
+        }
+        target
+    }
+
+    #[test]
+    fn compact() {
+        let vectors = [
+            (0x1d00ffff, "ffff0000000000000000000000000000000000000000000000000000"),
+            (0x207fffff, "7fffff0000000000000000000000000000000000000000000000000000000000"),
+            (0x05009234, "92340000"),
+            (0x04123456, "12345600"),
+            (0x03123456, "123456"),
+            (0x02123400, "1234"),
+            (0x01120000, "12"),
+            (0x2100ffff, "ffff000000000000000000000000000000000000000000000000000000000000"),
+        ];
+        for &(bits, target) in vectors.iter() {
+            assert_eq!(target_from_bits(bits), Some(hex(target)), "{:08x}", bits);
+            assert_eq!(bits_from_target(&hex(target)), bits, "{:08x}", bits);
+        }
+
+        // Precision below the mantissa is dropped
+        assert_eq!(target_from_bits(0x01123456), Some(hex("12")));
+        assert_eq!(bits_from_target(&hex("80")), 0x02008000);
+
+        // Negative, zero and overflowing targets
+        assert_eq!(target_from_bits(0x04923456), None);
+        assert_eq!(target_from_bits(0x00000000), None);
+        assert_eq!(target_from_bits(0x01003456), None);
+        assert_eq!(target_from_bits(0x21010000), None);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let t = hex("ffff0000000000000000000000000000000000000000000000000000");
+        let n = U256::from_target(&t);
+        assert_eq!(n.to_target(), t);
+        assert!(n > U256::from_u64(u64::max_value()));
+
+        let (q, r) = n.checked_mul_u64(3).unwrap().div_rem_u64(3);
+        assert_eq!((q, r), (n, 0));
+        assert_eq!(U256::from_u64(10).div_rem_u64(4), (U256::from_u64(2), 2));
+        assert_eq!(U256::from_u64(u64::max_value()).checked_add(&U256::from_u64(1)).unwrap().to_target(), hex("010000000000000000"));
+
+        let max = U256::from_target(&[0xff; HASH_LENGTH]);
+        assert_eq!(max.checked_add(&U256::from_u64(1)), None);
+        assert_eq!(max.checked_mul_u64(2), None);
+        assert!(U256::zero().is_zero());
+
+        assert_eq!(n.checked_mul_u64(7).unwrap().div(&n), U256::from_u64(7));
+        assert_eq!(max.div(&U256::from_u64(1)), max);
+        assert_eq!(max.div(&max), U256::from_u64(1));
+        assert_eq!(U256::from_u64(6).div(&U256::from_u64(7)), U256::zero());
+        assert_eq!(max.div(&U256::from_target(&hex("8000000000000000000000000000000000000000000000000000000000000000"))),
+                   U256::from_u64(1));
+        assert_eq!(max.div(&U256::from_target(&hex("10000000000000000"))), U256::from_target(&hex("ffffffffffffffffffffffffffffffffffffffffffffffff")));
+    }
+
+    #[test]
+    fn work() {
+        // Same values as Bitcoin's chainwork
+        assert_eq!(block_work(0x1d00ffff), U256::from_u64(0x0100010001));
+        assert_eq!(block_work(REGTEST_BITS), U256::from_u64(2));
+        assert_eq!(block_work(0x04923456), U256::zero());
+    }
+
+    #[test]
+    fn proof() {
+        let low = BlockHash::from_bytes(&hex("7fffff"));
+        let high = BlockHash::from_bytes(&[0xff; HASH_LENGTH]);
+        assert!(check_proof(&low, REGTEST_BITS));
+        assert!(check_proof(&low, 0x037fffff));
+        assert!(!check_proof(&low, 0x037ffffe));
+        assert!(!check_proof(&high, REGTEST_BITS));
+        assert!(!check_proof(&low, 0x04923456));
+    }
+}
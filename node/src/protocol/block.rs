@@ -0,0 +1,191 @@
+// Blocks and block headers.
+//
+// A block is a header plus the transactions it confirms. The header commits to
+// the transactions through their Merkle root, so the header alone identifies
+// the block: its hash is the SHA-256 of the header's canonical encoding.
+//
+use std::io;
+use std::io::prelude::*;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Sha256, Digest};
+use protocol::{HASH_LENGTH, Transaction, TxHash};
+
+/// Current block format version
+pub const BLOCK_VERSION: u32 = 1;
+
+/// Length of a header's canonical encoding
+pub const HEADER_SIZE: usize = 4 + HASH_LENGTH + HASH_LENGTH + 8 + 4 + 8;
+
+hash_type! {
+    /// SHA-256 of a block header
+    BlockHash, "a block hash as a 32-byte SHA-256 hash"
+}
+
+/// Block metadata; everything proof-of-work commits to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    /// Block format version, c.f. `BLOCK_VERSION`
+    pub version: u32,
+    /// Hash of the previous block, all zeroes for the genesis block
+    pub prev_hash: BlockHash,
+    /// Root of the Merkle tree of the block's transaction hashes
+    pub merkle_root: TxHash,
+    /// Unix time the block was created at
+    pub timestamp: u64,
+    /// Difficulty target in compact form
+    pub bits: u32,
+    /// Value miners vary to find a valid proof-of-work
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    /// Write the canonical encoding: every field in order, integers big-endian
+    pub fn write<W: Write>(&self, wr: &mut W) -> io::Result<()> {
+        wr.write_u32::<BigEndian>(self.version)?;
+        wr.write_all(self.prev_hash.as_bytes())?;
+        wr.write_all(self.merkle_root.as_bytes())?;
+        wr.write_u64::<BigEndian>(self.timestamp)?;
+        wr.write_u32::<BigEndian>(self.bits)?;
+        wr.write_u64::<BigEndian>(self.nonce)?;
+        Ok(())
+    }
+
+    /// Read a header back from its canonical encoding
+    pub fn read<R: Read>(rd: &mut R) -> io::Result<BlockHeader> {
+        let version = rd.read_u32::<BigEndian>()?;
+        let mut prev_hash = [0; HASH_LENGTH];
+        rd.read_exact(&mut prev_hash)?;
+        let mut merkle_root = [0; HASH_LENGTH];
+        rd.read_exact(&mut merkle_root)?;
+
+        Ok(BlockHeader {
+            version: version,
+            prev_hash: BlockHash::from_bytes(&prev_hash),
+            merkle_root: TxHash::from_bytes(&merkle_root),
+            timestamp: rd.read_u64::<BigEndian>()?,
+            bits: rd.read_u32::<BigEndian>()?,
+            nonce: rd.read_u64::<BigEndian>()?,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        self.write(&mut buf).unwrap();
+        buf
+    }
+
+    /// Hash using SHA-256
+    pub fn hash(&self) -> BlockHash {
+        let mut hasher = Sha256::default();
+        hasher.input(&self.to_bytes());
+        BlockHash::from_bytes(&hasher.result())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub txs: Vec<Transaction>,
+}
+
+impl Block {
+    /// Create a block on top of `prev_hash` confirming `txs`
+    pub fn new(prev_hash: BlockHash, timestamp: u64, bits: u32, txs: Vec<Transaction>) -> Block {
+        let mut block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_hash: prev_hash,
+                merkle_root: TxHash::new(),
+                timestamp: timestamp,
+                bits: bits,
+                nonce: 0,
+            },
+            txs: txs,
+        };
+        block.header.merkle_root = block.compute_merkle_root();
+        block
+    }
+
+    pub fn hash(&self) -> BlockHash {
+        self.header.hash()
+    }
+
+    /// Merkle root of the transactions actually in the block. Built from
+    /// wtxids, so the header commits to signatures as well.
+    pub fn compute_merkle_root(&self) -> TxHash {
+        merkle_root(&self.txs.iter().map(|tx| tx.wtxid()).collect::<Vec<_>>())
+    }
+}
+
+/// Root of the Merkle tree over the given hashes.
+///
+/// Each level hashes pairs of nodes together, pairing the last node with
+/// itself when a level has an odd count. No hashes give the zero hash.
+pub fn merkle_root(hashes: &[TxHash]) -> TxHash {
+    if hashes.is_empty() {
+        return TxHash::new();
+    }
+
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = level.chunks(2).map(|pair| {
+            let right = if pair.len() == 2 { &pair[1] } else { &pair[0] };
+            let mut hasher = Sha256::default();
+            hasher.input(pair[0].as_bytes());
+            hasher.input(right.as_bytes());
+            TxHash::from_bytes(&hasher.result())
+        }).collect();
+    }
+    level[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> BlockHeader {
+        BlockHeader {
+            version: BLOCK_VERSION,
+            prev_hash: BlockHash::from_bytes(&[1; HASH_LENGTH]),
+            merkle_root: TxHash::from_bytes(&[2; HASH_LENGTH]),
+            timestamp: 1_500_000_000,
+            bits: 0x207fffff,
+            nonce: 42,
+        }
+    }
+
+    #[test]
+    fn header_encoding() {
+        let h = header();
+        let bytes = h.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE);
+        assert_eq!(&bytes[..4], &[0, 0, 0, 1]);
+        assert_eq!(&bytes[bytes.len() - 8..], &[0, 0, 0, 0, 0, 0, 0, 42]);
+        assert_eq!(BlockHeader::read(&mut &bytes[..]).unwrap(), h);
+
+        // Every field is committed to
+        let mut other = header();
+        other.nonce += 1;
+        assert!(other.hash() != h.hash());
+        assert_eq!(header().hash(), h.hash());
+    }
+
+    #[test]
+    fn merkle() {
+        let a = TxHash::from_bytes(&[0xaa; HASH_LENGTH]);
+        let b = TxHash::from_bytes(&[0xbb; HASH_LENGTH]);
+        let c = TxHash::from_bytes(&[0xcc; HASH_LENGTH]);
+        let pair = |l: &TxHash, r: &TxHash| {
+            let mut hasher = Sha256::default();
+            hasher.input(l.as_bytes());
+            hasher.input(r.as_bytes());
+            TxHash::from_bytes(&hasher.result())
+        };
+
+        assert_eq!(merkle_root(&[]), TxHash::new());
+        assert_eq!(merkle_root(&[a]), a);
+        assert_eq!(merkle_root(&[a, b]), pair(&a, &b));
+        assert_eq!(merkle_root(&[a, b, c]), pair(&pair(&a, &b), &pair(&c, &c)));
+        assert!(merkle_root(&[b, a]) != merkle_root(&[a, b]));
+    }
+}
@@ -0,0 +1,444 @@
+// Node-to-node wire format.
+//
+// Every message is a fixed-size header followed by a MessagePack payload:
+// "BLOCK" | u32 version | u32 msg. type | u32 payload size | 4 byte checksum | payload
+// All integers are in network byte order; the checksum is the first 4 bytes
+// of the payload's SHA-256 hash.
+//
+use std::io;
+use std::io::prelude::*;
+use std::fmt;
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use rmps;
+use rmps::{Serializer as MPSerializer, Deserializer as MPDeserializer};
+use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+use sha2::{Sha256, Digest};
+use protocol::{MsgVersion, MsgVerack, MsgGetAddr, MsgAddr, MsgPing, MsgPong};
+use protocol::{MsgInv, MsgGetData, MsgNotFound, MsgShareTx, MsgBlock};
+use protocol::{MsgGetHeaders, MsgHeaders, MsgGetBlocks};
+
+/// Length of the magic string
+pub const MAGIC_LENGTH: usize = 5;
+
+/// Magic string every message starts with, one per network
+pub type Magic = [u8; MAGIC_LENGTH];
+
+/// Length of the payload checksum
+pub const CHECKSUM_LENGTH: usize = 4;
+
+/// Full header length, magic string included
+pub const HEADER_LENGTH: usize = MAGIC_LENGTH + 12 + CHECKSUM_LENGTH;
+
+/// Protocol version spoken by this node
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Oldest protocol version we can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 5;
+
+/// Largest payload we accept; bigger messages are rejected before being read
+pub const MAX_PAYLOAD_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Message type identifiers, as sent on the wire
+pub const MSG_VERSION: u32 = 1;
+pub const MSG_SHARE_TX: u32 = 2;
+pub const MSG_VERACK: u32 = 3;
+pub const MSG_GETADDR: u32 = 4;
+pub const MSG_ADDR: u32 = 5;
+pub const MSG_PING: u32 = 6;
+pub const MSG_PONG: u32 = 7;
+pub const MSG_INV: u32 = 8;
+pub const MSG_GETDATA: u32 = 9;
+pub const MSG_NOTFOUND: u32 = 10;
+pub const MSG_BLOCK: u32 = 11;
+pub const MSG_GETHEADERS: u32 = 12;
+pub const MSG_HEADERS: u32 = 13;
+pub const MSG_GETBLOCKS: u32 = 14;
+
+/// Message header, minus the magic string
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    /// Protocol version the sender used to encode the message
+    pub version: u32,
+    /// One of the `MSG_*` identifiers
+    pub msg_type: u32,
+    /// Payload size in bytes
+    pub size: u32,
+    /// Payload checksum, c.f. `checksum`
+    pub checksum: [u8; CHECKSUM_LENGTH],
+}
+
+impl Header {
+    /// Read and check a header off a stream
+    pub fn read<R: Read>(rd: &mut R, expected: &Magic) -> Result<Header, CodecError> {
+        let mut magic: [u8; MAGIC_LENGTH] = [0; MAGIC_LENGTH];
+
+        // A stream closed right at a message boundary is not an error
+        // per se; tell it apart from one that ends mid-message
+        let mut read = 0;
+        while read < MAGIC_LENGTH {
+            match rd.read(&mut magic[read..]) {
+                Ok(0) if read == 0 => return Err(CodecError::Closed),
+                Ok(0) => return Err(CodecError::Truncated),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(CodecError::from(e)),
+            }
+        }
+        if &magic != expected {
+            return Err(CodecError::BadMagic(magic));
+        }
+
+        let version = rd.read_u32::<NetworkEndian>()?;
+        let msg_type = rd.read_u32::<NetworkEndian>()?;
+        let size = rd.read_u32::<NetworkEndian>()?;
+        let mut checksum: [u8; CHECKSUM_LENGTH] = [0; CHECKSUM_LENGTH];
+        rd.read_exact(&mut checksum)?;
+
+        Ok(Header { version, msg_type, size, checksum })
+    }
+
+    /// Write the header, magic string included
+    pub fn write<W: Write>(&self, wr: &mut W, magic: &Magic) -> Result<(), CodecError> {
+        wr.write_all(magic)?;
+        wr.write_u32::<NetworkEndian>(self.version)?;
+        wr.write_u32::<NetworkEndian>(self.msg_type)?;
+        wr.write_u32::<NetworkEndian>(self.size)?;
+        wr.write_all(&self.checksum)?;
+        Ok(())
+    }
+}
+
+/// Every message two nodes can exchange
+#[derive(Debug)]
+pub enum Message {
+    Version(MsgVersion),
+    Verack(MsgVerack),
+    GetAddr(MsgGetAddr),
+    Addr(MsgAddr),
+    Ping(MsgPing),
+    Pong(MsgPong),
+    Inv(MsgInv),
+    GetData(MsgGetData),
+    NotFound(MsgNotFound),
+    ShareTx(MsgShareTx),
+    Block(MsgBlock),
+    GetHeaders(MsgGetHeaders),
+    Headers(MsgHeaders),
+    GetBlocks(MsgGetBlocks),
+}
+
+impl Message {
+    /// Wire identifier of the message
+    pub fn msg_type(&self) -> u32 {
+        match self {
+            &Message::Version(_) => MSG_VERSION,
+            &Message::Verack(_) => MSG_VERACK,
+            &Message::GetAddr(_) => MSG_GETADDR,
+            &Message::Addr(_) => MSG_ADDR,
+            &Message::Ping(_) => MSG_PING,
+            &Message::Pong(_) => MSG_PONG,
+            &Message::Inv(_) => MSG_INV,
+            &Message::GetData(_) => MSG_GETDATA,
+            &Message::NotFound(_) => MSG_NOTFOUND,
+            &Message::ShareTx(_) => MSG_SHARE_TX,
+            &Message::Block(_) => MSG_BLOCK,
+            &Message::GetHeaders(_) => MSG_GETHEADERS,
+            &Message::Headers(_) => MSG_HEADERS,
+            &Message::GetBlocks(_) => MSG_GETBLOCKS,
+        }
+    }
+
+    /// Human-readable name, for logging purposes
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Message::Version(_) => "version",
+            &Message::Verack(_) => "verack",
+            &Message::GetAddr(_) => "getaddr",
+            &Message::Addr(_) => "addr",
+            &Message::Ping(_) => "ping",
+            &Message::Pong(_) => "pong",
+            &Message::Inv(_) => "inv",
+            &Message::GetData(_) => "getdata",
+            &Message::NotFound(_) => "notfound",
+            &Message::ShareTx(_) => "sharetx",
+            &Message::Block(_) => "block",
+            &Message::GetHeaders(_) => "getheaders",
+            &Message::Headers(_) => "headers",
+            &Message::GetBlocks(_) => "getblocks",
+        }
+    }
+
+    /// MessagePack-encode the message payload
+    pub fn encode_payload(&self) -> Result<Vec<u8>, CodecError> {
+        let mut buf = Vec::new();
+        {
+            let mut se = MPSerializer::new(&mut buf);
+            match self {
+                &Message::Version(ref m) => m.serialize(&mut se)?,
+                &Message::Verack(ref m) => m.serialize(&mut se)?,
+                &Message::GetAddr(ref m) => m.serialize(&mut se)?,
+                &Message::Addr(ref m) => m.serialize(&mut se)?,
+                &Message::Ping(ref m) => m.serialize(&mut se)?,
+                &Message::Pong(ref m) => m.serialize(&mut se)?,
+                &Message::Inv(ref m) => m.serialize(&mut se)?,
+                &Message::GetData(ref m) => m.serialize(&mut se)?,
+                &Message::NotFound(ref m) => m.serialize(&mut se)?,
+                &Message::ShareTx(ref m) => m.serialize(&mut se)?,
+                &Message::Block(ref m) => m.serialize(&mut se)?,
+                &Message::GetHeaders(ref m) => m.serialize(&mut se)?,
+                &Message::Headers(ref m) => m.serialize(&mut se)?,
+                &Message::GetBlocks(ref m) => m.serialize(&mut se)?,
+            };
+        }
+        Ok(buf)
+    }
+
+    /// Decode a MessagePack payload of the given type
+    pub fn decode_payload(msg_type: u32, payload: &[u8]) -> Result<Message, CodecError> {
+        let mut de = MPDeserializer::new(payload);
+        let msg = match msg_type {
+            MSG_VERSION => Message::Version(Deserialize::deserialize(&mut de)?),
+            MSG_VERACK => Message::Verack(Deserialize::deserialize(&mut de)?),
+            MSG_GETADDR => Message::GetAddr(Deserialize::deserialize(&mut de)?),
+            MSG_ADDR => Message::Addr(Deserialize::deserialize(&mut de)?),
+            MSG_PING => Message::Ping(Deserialize::deserialize(&mut de)?),
+            MSG_PONG => Message::Pong(Deserialize::deserialize(&mut de)?),
+            MSG_INV => Message::Inv(Deserialize::deserialize(&mut de)?),
+            MSG_GETDATA => Message::GetData(Deserialize::deserialize(&mut de)?),
+            MSG_NOTFOUND => Message::NotFound(Deserialize::deserialize(&mut de)?),
+            MSG_SHARE_TX => Message::ShareTx(Deserialize::deserialize(&mut de)?),
+            MSG_BLOCK => Message::Block(Deserialize::deserialize(&mut de)?),
+            MSG_GETHEADERS => Message::GetHeaders(Deserialize::deserialize(&mut de)?),
+            MSG_HEADERS => Message::Headers(Deserialize::deserialize(&mut de)?),
+            MSG_GETBLOCKS => Message::GetBlocks(Deserialize::deserialize(&mut de)?),
+            _ => return Err(CodecError::UnknownType(msg_type)),
+        };
+        Ok(msg)
+    }
+}
+
+/// First 4 bytes of the payload's SHA-256 hash
+pub fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+    let mut hasher = Sha256::default();
+    hasher.input(payload);
+    let mut sum: [u8; CHECKSUM_LENGTH] = [0; CHECKSUM_LENGTH];
+    sum.copy_from_slice(&hasher.result()[..CHECKSUM_LENGTH]);
+    sum
+}
+
+/// Write a full message (header and payload) to a stream
+pub fn encode<W: Write>(wr: &mut W, magic: &Magic, version: u32, msg: &Message) -> Result<(), CodecError> {
+    let payload = msg.encode_payload()?;
+    if payload.len() > MAX_PAYLOAD_SIZE as usize {
+        return Err(CodecError::Oversize(payload.len() as u32));
+    }
+
+    let header = Header {
+        version: version,
+        msg_type: msg.msg_type(),
+        size: payload.len() as u32,
+        checksum: checksum(&payload),
+    };
+    header.write(wr, magic)?;
+    wr.write_all(&payload)?;
+    Ok(())
+}
+
+/// Read a full message off a stream, expecting the given magic string.
+///
+/// The payload is checked against the header checksum before being decoded.
+/// The payload of a message with an unknown type or a bad checksum is consumed
+/// before the error is returned, so the stream stays in sync.
+pub fn decode<R: Read>(rd: &mut R, magic: &Magic) -> Result<(Header, Message), CodecError> {
+    let header = Header::read(rd, magic)?;
+    if header.size > MAX_PAYLOAD_SIZE {
+        return Err(CodecError::Oversize(header.size));
+    }
+
+    let mut payload = vec![0; header.size as usize];
+    rd.read_exact(&mut payload)?;
+
+    let actual = checksum(&payload);
+    if actual != header.checksum {
+        return Err(CodecError::BadChecksum { expected: header.checksum, actual: actual });
+    }
+
+    let msg = Message::decode_payload(header.msg_type, &payload)?;
+    Ok((header, msg))
+}
+
+/// Failure to read or write a protocol message
+#[derive(Debug)]
+pub enum CodecError {
+    /// Stream was closed cleanly before a new message started
+    Closed,
+    /// Message does not start with the magic string
+    BadMagic([u8; MAGIC_LENGTH]),
+    /// Message type is not known to this node
+    UnknownType(u32),
+    /// Payload is larger than `MAX_PAYLOAD_SIZE`
+    Oversize(u32),
+    /// Stream ended in the middle of a message
+    Truncated,
+    /// Payload does not match the header checksum
+    BadChecksum {
+        expected: [u8; CHECKSUM_LENGTH],
+        actual: [u8; CHECKSUM_LENGTH]
+    },
+    /// Payload could not be MessagePack-encoded
+    Encode(rmps::encode::Error),
+    /// Payload could not be MessagePack-decoded
+    Decode(rmps::decode::Error),
+    /// Any other I/O failure
+    Io(io::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CodecError::Closed => write!(f, "connection closed"),
+            &CodecError::BadMagic(ref m) => write!(f, "bad magic string {:?}", m),
+            &CodecError::UnknownType(t) => write!(f, "unknown message type {}", t),
+            &CodecError::Oversize(sz) => write!(f, "payload of {} bytes exceeds the {} bytes limit", sz, MAX_PAYLOAD_SIZE),
+            &CodecError::Truncated => write!(f, "stream ended mid-message"),
+            &CodecError::BadChecksum { ref expected, ref actual } =>
+                write!(f, "payload checksum mismatch (header says {:?}, payload hashes to {:?})", expected, actual),
+            &CodecError::Encode(ref e) => write!(f, "could not encode payload: {}", e),
+            &CodecError::Decode(ref e) => write!(f, "could not decode payload: {}", e),
+            &CodecError::Io(ref e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for CodecError {
+    fn description(&self) -> &str {
+        "protocol message codec error"
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> CodecError {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => CodecError::Truncated,
+            _ => CodecError::Io(e)
+        }
+    }
+}
+
+impl From<rmps::encode::Error> for CodecError {
+    fn from(e: rmps::encode::Error) -> CodecError {
+        CodecError::Encode(e)
+    }
+}
+
+impl From<rmps::decode::Error> for CodecError {
+    fn from(e: rmps::decode::Error) -> CodecError {
+        CodecError::Decode(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use protocol::{BlockHash, SERVICE_NETWORK, USER_AGENT};
+
+    const MAGIC: &Magic = b"BLOCK";
+
+    fn version() -> Message {
+        Message::Version(MsgVersion::new(SERVICE_NETWORK, 42, 0xdeadbeef, 7878, BlockHash::from_bytes(&[7; 32])))
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = Vec::new();
+        encode(&mut buf, MAGIC, PROTOCOL_VERSION, &version()).unwrap();
+        encode(&mut buf, MAGIC, PROTOCOL_VERSION, &Message::Verack(MsgVerack {})).unwrap();
+        assert_eq!(&buf[..MAGIC_LENGTH], MAGIC);
+
+        let mut rd = Cursor::new(&buf);
+
+        let (header, msg) = decode(&mut rd, MAGIC).unwrap();
+        assert_eq!(header.version, PROTOCOL_VERSION);
+        assert_eq!(header.msg_type, MSG_VERSION);
+        match msg {
+            Message::Version(v) => {
+                assert_eq!(v.services, SERVICE_NETWORK);
+                assert_eq!(v.height, 42);
+                assert_eq!(v.nonce, 0xdeadbeef);
+                assert_eq!(v.user_agent, USER_AGENT);
+                assert_eq!(v.port, 7878);
+                assert_eq!(v.genesis, BlockHash::from_bytes(&[7; 32]));
+            },
+            _ => panic!("decoded the wrong message type")
+        }
+
+        let (header, msg) = decode(&mut rd, MAGIC).unwrap();
+        assert_eq!(header.msg_type, MSG_VERACK);
+        match msg {
+            Message::Verack(_) => (),
+            _ => panic!("decoded the wrong message type")
+        }
+        assert_eq!(rd.position() as usize, buf.len());
+    }
+
+    #[test]
+    fn errors() {
+        let mut buf = Vec::new();
+        encode(&mut buf, MAGIC, PROTOCOL_VERSION, &version()).unwrap();
+
+        // Clean close vs. truncated stream
+        match decode(&mut Cursor::new(&buf[..0]), MAGIC) {
+            Err(CodecError::Closed) => (),
+            r => panic!("expected Closed, got {:?}", r)
+        }
+        match decode(&mut Cursor::new(&buf[..buf.len() - 1]), MAGIC) {
+            Err(CodecError::Truncated) => (),
+            r => panic!("expected Truncated, got {:?}", r)
+        }
+
+        // Bad magic
+        let mut bad = buf.clone();
+        bad[0] = b'X';
+        match decode(&mut Cursor::new(&bad), MAGIC) {
+            Err(CodecError::BadMagic(m)) => assert_eq!(&m, b"XLOCK"),
+            r => panic!("expected BadMagic, got {:?}", r)
+        }
+
+        // Another network's magic
+        match decode(&mut Cursor::new(&buf), b"BLTST") {
+            Err(CodecError::BadMagic(m)) => assert_eq!(&m, MAGIC),
+            r => panic!("expected BadMagic, got {:?}", r)
+        }
+
+        // Unknown message type
+        let mut bad = buf.clone();
+        bad[MAGIC_LENGTH + 7] = 0xff;
+        match decode(&mut Cursor::new(&bad), MAGIC) {
+            Err(CodecError::UnknownType(t)) => assert_eq!(t, 0xff),
+            r => panic!("expected UnknownType, got {:?}", r)
+        }
+
+        // Corrupted payload
+        let mut bad = buf.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 0x01;
+        match decode(&mut Cursor::new(&bad), MAGIC) {
+            Err(CodecError::BadChecksum { expected, actual }) => {
+                assert_eq!(&expected, &buf[HEADER_LENGTH - CHECKSUM_LENGTH..HEADER_LENGTH]);
+                assert_eq!(actual, checksum(&bad[HEADER_LENGTH..]));
+            },
+            r => panic!("expected BadChecksum, got {:?}", r)
+        }
+
+        // Oversize payload
+        let mut bad = Vec::new();
+        Header { version: PROTOCOL_VERSION, msg_type: MSG_SHARE_TX, size: MAX_PAYLOAD_SIZE + 1, checksum: [0; CHECKSUM_LENGTH] }
+            .write(&mut bad, MAGIC).unwrap();
+        match decode(&mut Cursor::new(&bad), MAGIC) {
+            Err(CodecError::Oversize(sz)) => assert_eq!(sz, MAX_PAYLOAD_SIZE + 1),
+            r => panic!("expected Oversize, got {:?}", r)
+        }
+    }
+}
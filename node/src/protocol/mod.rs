@@ -0,0 +1,625 @@
+use serde::{Serialize, Deserialize};
+use ed25519_dalek::{PublicKey, Signature, Keypair, SIGNATURE_LENGTH};
+use sha2::{Sha256, Sha512, Digest};
+use base58;
+use chainparams;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, WriteBytesExt};
+use ipnet::IpNet;
+
+/// Define a newtype around a raw SHA-256 hash, serialized as a byte string
+macro_rules! hash_type {
+    ($(#[$attr:meta])* $name:ident, $expecting:expr) => {
+        $(#[$attr])*
+        #[derive(Shrinkwrap, Clone, Copy, PartialEq, Eq, Hash)]
+        pub struct $name([u8; HASH_LENGTH]);
+
+        impl $name {
+            pub fn new() -> $name {
+                $name([0; HASH_LENGTH])
+            }
+
+            /// Load from raw bytes
+            pub fn from_bytes(b: &[u8]) -> $name {
+                let mut hash = $name::new();
+                hash.0.copy_from_slice(&b);
+                hash
+            }
+
+            #[inline]
+            pub fn as_bytes(&self) -> &[u8; HASH_LENGTH] {
+                &self.0
+            }
+        }
+
+        impl ::std::fmt::Debug for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                write!(f, "{}", ::base58::encode(&self[..]))
+            }
+        }
+
+        impl ::serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: ::serde::Serializer,
+            {
+                serializer.serialize_bytes(&self.0)
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: ::serde::Deserializer<'de>
+            {
+                use serde::de::{Error, Visitor};
+                struct HashVisitor;
+
+                impl<'de> Visitor<'de> for HashVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                        formatter.write_str($expecting)
+                    }
+
+                    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<$name, E>
+                        where E: Error
+                    {
+                        match bytes.len() {
+                            HASH_LENGTH => Ok($name::from_bytes(&bytes[..])),
+                            _ => Err(E::invalid_length(bytes.len(), &self))
+                        }
+                    }
+                }
+
+                deserializer.deserialize_bytes(HashVisitor)
+            }
+        }
+    }
+}
+
+pub mod codec;
+pub mod block;
+pub mod sighash;
+
+use self::codec::Message;
+pub use self::block::{Block, BlockHeader, BlockHash};
+pub use self::sighash::{SigHashBase, SigHashType};
+use peer::PeerId;
+
+/// We use SHA-256 for most hashing purposes; 32-byte output
+pub const HASH_LENGTH: usize = 32;
+
+/// Seconds since the Unix epoch
+pub fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0
+    }
+}
+
+/// Public-key hash
+#[derive(Shrinkwrap, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address([u8; HASH_LENGTH]);
+
+impl Address {
+    /// Zero-initialize an address
+    pub fn new() -> Address {
+        Address([0; HASH_LENGTH])
+    }
+
+    /// Address owned by a key: the SHA-256 of the public key
+    pub fn from_public_key(pk: &PublicKey) -> Address {
+        let mut hasher = Sha256::default();
+        hasher.input(pk.as_bytes());
+        Address::from_bytes(&hasher.result())
+    }
+
+    /// Encode in checksummed base58, for the selected network
+    pub fn display(&self) -> String {
+        base58::encode_check(chainparams::params().address_version, &self.0)
+    }
+
+    /// Load from hash (raw bytes)
+    pub fn from_bytes(b: &[u8]) -> Address {
+        let mut addr = Address::new();
+        addr.0.copy_from_slice(b);
+        addr
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; HASH_LENGTH] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.display())
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.display())
+    }
+}
+
+/// Failure to parse an address
+#[derive(Debug, PartialEq)]
+pub enum AddressError {
+    Encoding(base58::CheckError),
+    /// Address belongs to the network with this version byte
+    WrongNetwork(u8),
+    /// Payload is not a public-key hash
+    WrongLength(usize),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &AddressError::Encoding(ref e) => write!(f, "malformed address: {}", e),
+            &AddressError::WrongNetwork(v) => write!(f, "address is for another network (version {})", v),
+            &AddressError::WrongLength(len) => write!(f, "address holds {} bytes instead of {}", len, HASH_LENGTH),
+        }
+    }
+}
+
+impl Error for AddressError {
+    fn description(&self) -> &str {
+        "invalid address"
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    /// Parse an address of the selected network
+    fn from_str(s: &str) -> Result<Address, AddressError> {
+        let (version, payload) = base58::decode_check(s).map_err(AddressError::Encoding)?;
+        if version != chainparams::params().address_version {
+            return Err(AddressError::WrongNetwork(version));
+        }
+        if payload.len() != HASH_LENGTH {
+            return Err(AddressError::WrongLength(payload.len()));
+        }
+        Ok(Address::from_bytes(&payload))
+    }
+}
+
+/// Transaction output
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Output {
+    /// Amount of currency units to send
+    pub amount: u64,
+    /// Destination address
+    pub creditor: Address,
+}
+
+hash_type! {
+    /// Special SHA-256 of a transaction
+    TxHash, "a transaction hash as a 32-byte SHA-256 hash"
+}
+
+/// Source of credit in a transaction
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct Input {
+    /// Hash of the referenced transaction
+    pub tx: TxHash,
+    /// Index of output referenced in the transaction
+    pub index: u8,
+    /// Public key of the referenced output's creditor
+    pub owner: PublicKey,
+    /// Unlocking signature by `owner`
+    pub signature: Signature,
+    /// What the signature commits to, c.f. `SigHashType`
+    pub sighash: u8,
+}
+
+/// What makes a coinbase transaction unique
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct CoinbaseData {
+    /// Height of the block the coinbase belongs to
+    pub height: u64,
+    /// Free value miners roll once they run out of header nonces
+    pub extra_nonce: u64,
+}
+
+/// Transaction object data
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transaction {
+    /// Set for coinbase transactions only
+    coinbase: Option<CoinbaseData>,
+    /// List of transaction inputs
+    inputs: Vec<Input>,
+    /// List of transaction outputs
+    outputs: Vec<Output>,
+}
+
+
+impl Transaction {
+    /// Create empty transaction
+    pub fn new() -> Transaction {
+        Transaction {
+            coinbase: None,
+            inputs: Vec::new(),
+            outputs: Vec::new()
+        }
+    }
+
+    /// Create the transaction minting `amount` new currency units for the
+    /// miner of the block at `height`
+    pub fn coinbase(height: u64, amount: u64, miner: Address) -> Transaction {
+        Transaction {
+            coinbase: Some(CoinbaseData { height: height, extra_nonce: 0 }),
+            inputs: Vec::new(),
+            outputs: vec!(Output { amount: amount, creditor: miner })
+        }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.coinbase.is_some()
+    }
+
+    pub fn coinbase_data(&self) -> Option<&CoinbaseData> {
+        self.coinbase.as_ref()
+    }
+
+    /// Change the extra nonce of a coinbase transaction; changes its hash
+    pub fn set_extra_nonce(&mut self, extra_nonce: u64) {
+        if let Some(ref mut data) = self.coinbase {
+            data.extra_nonce = extra_nonce;
+        }
+    }
+
+    /// Spend output `index` of transaction `tx`, owned by `owner`; unsigned
+    /// until `sign` is called
+    pub fn add_input(&mut self, tx: TxHash, index: u8, owner: PublicKey) {
+        self.inputs.push(Input {
+            tx: tx,
+            index: index,
+            owner: owner,
+            signature: Signature::from_bytes(&[0; SIGNATURE_LENGTH]).unwrap(),
+            sighash: SigHashType::ALL.to_u8(),
+        });
+    }
+
+    /// Send `amount` currency units to `creditor`
+    pub fn add_output(&mut self, amount: u64, creditor: Address) {
+        self.outputs.push(Output {
+            amount: amount,
+            creditor: creditor
+        });
+    }
+
+    pub fn inputs(&self) -> &[Input] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[Output] {
+        &self.outputs
+    }
+
+    /// Hash using SHA-256
+    pub fn hash(&self) -> TxHash {
+        let mut wtr = vec![];
+
+        // Append height + extra nonce for coinbases
+        if let Some(ref data) = self.coinbase {
+            wtr.write_u64::<BigEndian>(data.height).unwrap();
+            wtr.write_u64::<BigEndian>(data.extra_nonce).unwrap();
+        }
+
+        // Append tx_hash + tx_index + owner's public key for every input
+        for inp in &self.inputs {
+            wtr.extend_from_slice(inp.tx.as_bytes());
+            wtr.write_u8(inp.index).unwrap();
+            wtr.extend_from_slice(inp.owner.as_bytes());
+        }
+
+        // Append amount + creditor's address for every output
+        for outp in &self.outputs {
+            wtr.write_u64::<BigEndian>(outp.amount).unwrap();
+            wtr.extend_from_slice(outp.creditor.as_bytes());
+        }
+
+        // Hash resulting byte array
+        let mut hasher = Sha256::default();
+        hasher.input(&wtr);
+        TxHash::from_bytes(&hasher.result())
+    }
+
+    /// Hash covering the signatures too, unlike `hash`
+    pub fn wtxid(&self) -> TxHash {
+        let mut hasher = Sha256::default();
+        hasher.input(self.hash().as_bytes());
+        for inp in &self.inputs {
+            hasher.input(&inp.signature.to_bytes()[..]);
+            hasher.input(&[inp.sighash]);
+        }
+        TxHash::from_bytes(&hasher.result())
+    }
+
+    /// Hash signed by input `index`, c.f. `SigHashType`; `amounts` are
+    /// those of the outputs spent by each input. `None` if the input's
+    /// signature hash type is invalid, or `SINGLE` with no matching output.
+    pub fn signature_hash(&self, index: usize, amounts: &[u64]) -> Option<TxHash> {
+        let input = self.inputs.get(index)?;
+        let amount = *amounts.get(index)?;
+        let sighash = SigHashType::from_u8(input.sighash)?;
+        if amounts.len() != self.inputs.len() {
+            return None;
+        }
+
+        let mut wtr = vec![input.sighash];
+        wtr.extend_from_slice(input.owner.as_bytes());
+
+        // Other inputs and what they spend
+        if !sighash.anyone_can_pay {
+            let mut inputs = vec![];
+            let mut spent = vec![];
+            for (inp, &amount) in self.inputs.iter().zip(amounts) {
+                inputs.extend_from_slice(inp.tx.as_bytes());
+                inputs.write_u8(inp.index).unwrap();
+                inputs.extend_from_slice(inp.owner.as_bytes());
+                spent.write_u64::<BigEndian>(amount).unwrap();
+            }
+            wtr.extend_from_slice(&Sha256::digest(&inputs));
+            wtr.extend_from_slice(&Sha256::digest(&spent));
+        }
+
+        // This input and what it spends
+        wtr.extend_from_slice(input.tx.as_bytes());
+        wtr.write_u8(input.index).unwrap();
+        wtr.write_u64::<BigEndian>(amount).unwrap();
+
+        let outputs: &[Output] = match sighash.base {
+            SigHashBase::All => &self.outputs,
+            SigHashBase::None => &[],
+            SigHashBase::Single => ::std::slice::from_ref(self.outputs.get(index)?),
+        };
+        if sighash.base != SigHashBase::None {
+            let mut committed = vec![];
+            for outp in outputs {
+                committed.write_u64::<BigEndian>(outp.amount).unwrap();
+                committed.extend_from_slice(outp.creditor.as_bytes());
+            }
+            wtr.extend_from_slice(&Sha256::digest(&committed));
+        }
+
+        Some(TxHash::from_bytes(&Sha256::digest(&wtr)))
+    }
+
+    /// Sign every input owned by `kp`, committing to the whole transaction;
+    /// `amounts` are those of the outputs spent by each input. Inputs owned
+    /// by other keys are left for their owners to sign.
+    pub fn sign(&mut self, kp: Keypair, amounts: &[u64]) {
+        for i in 0..self.inputs.len() {
+            if self.inputs[i].owner == kp.public {
+                self.sign_input(i, &kp, amounts, SigHashType::ALL);
+            }
+        }
+    }
+
+    /// Sign input `index` only, with the given signature hash type; false if
+    /// `kp` doesn't own it or it doesn't apply, c.f. `signature_hash`
+    pub fn sign_input(&mut self, index: usize, kp: &Keypair, amounts: &[u64], sighash: SigHashType) -> bool {
+        if index >= self.inputs.len() || self.inputs[index].owner != kp.public {
+            return false;
+        }
+        self.inputs[index].sighash = sighash.to_u8();
+        match self.signature_hash(index, amounts) {
+            Some(hash) => {
+                self.inputs[index].signature = kp.sign::<Sha512>(hash.as_bytes());
+                true
+            },
+            None => false
+        }
+    }
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Node {
+    pub addr: IpNet,
+    pub port: u16
+}
+
+impl Node {
+    pub fn from_socket_addr(addr: &SocketAddr) -> Node {
+        Node {
+            addr: IpNet::from(addr.ip()),
+            port: addr.port()
+        }
+    }
+
+    /// Address to connect to
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr.addr(), self.port)
+    }
+}
+
+/// Service bit: node keeps the full chain and serves blocks to peers
+pub const SERVICE_NETWORK: u64 = 1 << 0;
+
+/// Software name and version announced to peers
+pub const USER_AGENT: &str = concat!("/blockchain:", env!("CARGO_PKG_VERSION"), "/");
+
+/// Opening message of every connection, sent by both sides
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MsgVersion {
+    /// Highest protocol version the sender speaks
+    pub version: u32,
+    /// Bitfield of `SERVICE_*` flags
+    pub services: u64,
+    /// Height of the sender's best chain
+    pub height: u64,
+    /// Random value identifying the sending node, to detect self-connections
+    pub nonce: u64,
+    /// Free-form software identifier, c.f. `USER_AGENT`
+    pub user_agent: String,
+    /// Port the sender accepts connections on, 0 if it does not listen
+    pub port: u16,
+    /// Hash of the sender's genesis block; nodes on other chains are dropped
+    pub genesis: BlockHash,
+}
+
+impl MsgVersion {
+    pub fn new(services: u64, height: u64, nonce: u64, port: u16, genesis: BlockHash) -> MsgVersion {
+        MsgVersion {
+            version: codec::PROTOCOL_VERSION,
+            services: services,
+            height: height,
+            nonce: nonce,
+            user_agent: String::from(USER_AGENT),
+            port: port,
+            genesis: genesis,
+        }
+    }
+}
+
+/// Acknowledges the peer's `MsgVersion`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct MsgVerack {}
+
+/// Most addresses a single `addr` message may carry
+pub const MAX_ADDR: usize = 1000;
+
+/// A node's address, along with when it was last seen alive
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NetAddr {
+    pub node: Node,
+    /// Unix time
+    pub last_seen: u64,
+}
+
+/// Asks the peer for addresses of other nodes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct MsgGetAddr {}
+
+/// Addresses of other nodes, either unsolicited or in answer to `MsgGetAddr`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MsgAddr {
+    pub addrs: Vec<NetAddr>
+}
+
+/// Keepalive probe; the peer answers with a `MsgPong` carrying the same nonce
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct MsgPing {
+    pub nonce: u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct MsgPong {
+    pub nonce: u64
+}
+
+/// Most items a single `inv`, `getdata` or `notfound` message may carry
+pub const MAX_INV: usize = 5000;
+
+/// Reference to an object peers can ask each other for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvItem {
+    Tx(TxHash),
+    /// Blocks are announced this way, but downloaded headers-first
+    Block(BlockHash),
+}
+
+/// Announces objects the sender has
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MsgInv {
+    pub items: Vec<InvItem>
+}
+
+/// Asks for announced objects; transactions are sent back with `MsgShareTx`,
+/// blocks each with a `MsgBlock`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MsgGetData {
+    pub items: Vec<InvItem>
+}
+
+/// Answers `MsgGetData` for the objects the sender does not have
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MsgNotFound {
+    pub items: Vec<InvItem>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MsgShareTx {
+    pub txs: Vec<Transaction>
+}
+
+/// A block, e.g. one the sender just mined or accepted
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MsgBlock {
+    pub block: Block
+}
+
+/// Most headers a single `headers` message may carry
+pub const MAX_HEADERS: usize = 2000;
+
+/// Most blocks a single `getblocks` message may ask for
+pub const MAX_GETBLOCKS: usize = 128;
+
+/// Asks for the headers following the first locator hash the peer knows of
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MsgGetHeaders {
+    /// Hashes of our chain, newest first, growing further apart; c.f. `Chain::locator`
+    pub locator: Vec<BlockHash>,
+    /// Last header wanted; all zeroes for as many as fit
+    pub stop: BlockHash,
+}
+
+/// Consecutive headers of the sender's best chain, in answer to `MsgGetHeaders`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MsgHeaders {
+    pub headers: Vec<BlockHeader>
+}
+
+/// Asks for blocks by hash; each one the peer has comes back in a `MsgBlock`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MsgGetBlocks {
+    pub hashes: Vec<BlockHash>
+}
+
+
+/// Handle a message from a peer that completed the handshake
+pub fn handle_message(peer: PeerId, msg: Message) {
+    match msg {
+        Message::Version(_) | Message::Verack(_) => {
+            println!("Peer {} repeated its `{}` message, ignoring", peer, msg.name());
+        },
+        _ => println!("Unhandled message type {}", msg.msg_type())
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SecretKey;
+    use chainparams::ChainParams;
+
+    #[test]
+    fn address_encoding() {
+        let secret = SecretKey::from_bytes(&[1; 32]).unwrap();
+        let addr = Address::from_public_key(&PublicKey::from_secret::<Sha512>(&secret));
+        let encoded = addr.display();
+        assert_eq!(encoded.parse::<Address>(), Ok(addr));
+
+        // Typos and other networks' addresses are rejected
+        let mut typo = encoded.clone().into_bytes();
+        typo[10] = if typo[10] == b'x' { b'y' } else { b'x' };
+        assert_eq!(String::from_utf8(typo).unwrap().parse::<Address>(),
+                   Err(AddressError::Encoding(base58::CheckError::BadChecksum)));
+
+        let testnet = base58::encode_check(ChainParams::testnet().address_version, addr.as_bytes());
+        assert_eq!(testnet.parse::<Address>(), Err(AddressError::WrongNetwork(ChainParams::testnet().address_version)));
+
+        let short = base58::encode_check(ChainParams::mainnet().address_version, &addr.as_bytes()[1..]);
+        assert_eq!(short.parse::<Address>(), Err(AddressError::WrongLength(HASH_LENGTH - 1)));
+    }
+}
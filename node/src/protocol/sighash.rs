@@ -0,0 +1,190 @@
+// Signature hash types.
+//
+// Each input signs its own hash of the transaction, c.f.
+// `Transaction::signature_hash`. The hash commits to the spending input, the
+// amount it spends and the signing key, and, depending on the input's
+// signature hash type, to the rest:
+//
+// - `ALL`: every output
+// - `NONE`: no output; anyone may redirect the funds
+// - `SINGLE`: the output at the input's own index only
+//
+// plus every other input and the amounts they spend, unless the
+// `ANYONECANPAY` flag is set, in which case other inputs may be added or
+// taken out freely.
+//
+use std::fmt;
+
+/// Outputs a signature commits to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SigHashBase {
+    All,
+    None,
+    Single,
+}
+
+/// Which parts of a transaction an input's signature commits to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SigHashType {
+    pub base: SigHashBase,
+    /// Commit to the spending input alone, not the others
+    pub anyone_can_pay: bool,
+}
+
+const SIGHASH_ALL: u8 = 1;
+const SIGHASH_NONE: u8 = 2;
+const SIGHASH_SINGLE: u8 = 3;
+const SIGHASH_ANYONECANPAY: u8 = 0x80;
+
+impl SigHashType {
+    /// The default: everything
+    pub const ALL: SigHashType = SigHashType { base: SigHashBase::All, anyone_can_pay: false };
+
+    pub fn new(base: SigHashBase, anyone_can_pay: bool) -> SigHashType {
+        SigHashType { base: base, anyone_can_pay: anyone_can_pay }
+    }
+
+    /// Decode the flag an input carries; `None` if it isn't a valid one
+    pub fn from_u8(flag: u8) -> Option<SigHashType> {
+        let base = match flag & !SIGHASH_ANYONECANPAY {
+            SIGHASH_ALL => SigHashBase::All,
+            SIGHASH_NONE => SigHashBase::None,
+            SIGHASH_SINGLE => SigHashBase::Single,
+            _ => return None
+        };
+        Some(SigHashType::new(base, flag & SIGHASH_ANYONECANPAY != 0))
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        let base = match self.base {
+            SigHashBase::All => SIGHASH_ALL,
+            SigHashBase::None => SIGHASH_NONE,
+            SigHashBase::Single => SIGHASH_SINGLE,
+        };
+        if self.anyone_can_pay { base | SIGHASH_ANYONECANPAY } else { base }
+    }
+}
+
+impl fmt::Display for SigHashType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let base = match self.base {
+            SigHashBase::All => "ALL",
+            SigHashBase::None => "NONE",
+            SigHashBase::Single => "SINGLE",
+        };
+        if self.anyone_can_pay {
+            write!(f, "{}|ANYONECANPAY", base)
+        } else {
+            write!(f, "{}", base)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{Address, Transaction, TxHash};
+    use test_util::keypair;
+
+    /// Two inputs spending 50 and 70, two outputs
+    fn transaction() -> Transaction {
+        let mut tx = Transaction::new();
+        tx.add_input(TxHash::from_bytes(&[1; 32]), 0, keypair(7).public);
+        tx.add_input(TxHash::from_bytes(&[2; 32]), 3, keypair(7).public);
+        tx.add_output(100, Address::from_bytes(&[3; 32]));
+        tx.add_output(15, Address::from_bytes(&[4; 32]));
+        tx
+    }
+
+    const AMOUNTS: [u64; 2] = [50, 70];
+
+    fn sighash(tx: &Transaction, index: usize, base: SigHashBase, anyone_can_pay: bool) -> Option<TxHash> {
+        let mut tx = tx.clone();
+        tx.sign_input(index, &keypair(7), &AMOUNTS, SigHashType::new(base, anyone_can_pay));
+        tx.signature_hash(index, &AMOUNTS)
+    }
+
+    #[test]
+    fn flags() {
+        for &flag in &[0x01, 0x02, 0x03, 0x81, 0x82, 0x83] {
+            assert_eq!(SigHashType::from_u8(flag).unwrap().to_u8(), flag);
+        }
+        for &flag in &[0x00, 0x04, 0x80, 0x41, 0xff] {
+            assert_eq!(SigHashType::from_u8(flag), None);
+        }
+        assert_eq!(format!("{}", SigHashType::new(SigHashBase::Single, true)), "SINGLE|ANYONECANPAY");
+    }
+
+    #[test]
+    fn vectors() {
+        let tx = transaction();
+        let vectors = [
+            (SigHashBase::All, false, "5iLAg9bQzGpFkinjVC9vj1u61evgka9Vh9uE9ojZLkEr"),
+            (SigHashBase::None, false, "3hTFHRfdFVWxBoYpn7LFm9iyahdLfbtE8m9t2ukWSJ1Y"),
+            (SigHashBase::Single, false, "8iSYsrfhrpkeXXq4W4tvBMHtpDt8mJzuK5C3dvvaH4D2"),
+            (SigHashBase::All, true, "2XgTbKZaWAYKPJaSb1uMDRQJGcDrRfm8yMiGz4kcNVnp"),
+            (SigHashBase::None, true, "7cR3GM1o49ZEPH5ZSanjShsrc3jSEQ2t1GP1G3gRH44c"),
+            (SigHashBase::Single, true, "ChM7boqwgCqLoZB6cJq7xLVjUcukumR5NQYguaKHYsvb"),
+        ];
+        for &(base, anyone_can_pay, expected) in vectors.iter() {
+            let hash = sighash(&tx, 1, base, anyone_can_pay).unwrap();
+            assert_eq!(format!("{:?}", hash), expected, "{}", SigHashType::new(base, anyone_can_pay));
+        }
+    }
+
+    #[test]
+    fn commitments() {
+        let tx = transaction();
+        let modes = [
+            (SigHashBase::All, false), (SigHashBase::None, false), (SigHashBase::Single, false),
+            (SigHashBase::All, true), (SigHashBase::None, true), (SigHashBase::Single, true),
+        ];
+
+        // Another input added, or another amount spent
+        let mut more_inputs = transaction();
+        more_inputs.add_input(TxHash::from_bytes(&[5; 32]), 0, keypair(7).public);
+        let more_amounts = [51, 70];
+        // Another output added, or the one at the input's index changed
+        let mut more_outputs = transaction();
+        more_outputs.add_output(1, Address::from_bytes(&[6; 32]));
+        let mut own_output = transaction();
+        own_output.outputs[1].amount = 16;
+
+        for &(base, anyone_can_pay) in modes.iter() {
+            let hash = sighash(&tx, 1, base, anyone_can_pay).unwrap();
+            let flag = SigHashType::new(base, anyone_can_pay);
+            let mut with_input = more_inputs.clone();
+            with_input.sign_input(1, &keypair(7), &[50, 70, 1], flag);
+            let mut with_amount = tx.clone();
+            with_amount.sign_input(1, &keypair(7), &more_amounts, flag);
+
+            assert_eq!(with_input.signature_hash(1, &[50, 70, 1]) == Some(hash), anyone_can_pay, "{}", flag);
+            assert_eq!(with_amount.signature_hash(1, &more_amounts) == Some(hash), anyone_can_pay, "{}", flag);
+            assert_eq!(sighash(&more_outputs, 1, base, anyone_can_pay) == Some(hash), base != SigHashBase::All, "{}", flag);
+            assert_eq!(sighash(&own_output, 1, base, anyone_can_pay) == Some(hash), base == SigHashBase::None, "{}", flag);
+        }
+
+        // SINGLE needs an output at the input's index; amounts must match inputs
+        let mut single = transaction();
+        single.outputs.truncate(1);
+        assert!(!single.sign_input(1, &keypair(7), &AMOUNTS, SigHashType::new(SigHashBase::Single, false)));
+        assert_eq!(tx.signature_hash(0, &AMOUNTS[..1]), None);
+        assert_eq!(tx.signature_hash(2, &AMOUNTS), None);
+    }
+
+    #[test]
+    fn wtxid() {
+        let mut tx = transaction();
+        let (hash, wtxid) = (tx.hash(), tx.wtxid());
+        assert!(wtxid != hash);
+
+        // Signatures change the wtxid only
+        tx.sign(keypair(7), &AMOUNTS);
+        assert_eq!(tx.hash(), hash);
+        assert!(tx.wtxid() != wtxid);
+        let signed = tx.wtxid();
+        tx.sign_input(0, &keypair(7), &AMOUNTS, SigHashType::new(SigHashBase::None, false));
+        assert_eq!(tx.hash(), hash);
+        assert!(tx.wtxid() != signed);
+    }
+}
@@ -0,0 +1,241 @@
+// Inventory-based relay.
+//
+// Peers announce objects by hash (`inv`), and only the objects we don't have
+// yet are fetched (`getdata`). We remember which inventory every peer is known
+// to have, so an object is never announced back to a peer that sent it or
+// already announced it to us.
+//
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use protocol::InvItem;
+use peer::PeerId;
+
+/// Most items remembered per peer; the oldest are forgotten first
+pub const MAX_KNOWN_INVENTORY: usize = 50000;
+
+/// How long a peer has to deliver an item we asked for
+pub const GETDATA_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bounded set of inventory items, forgetting the oldest ones first
+struct KnownInventory {
+    items: HashSet<InvItem>,
+    order: VecDeque<InvItem>,
+}
+
+impl KnownInventory {
+    fn new() -> KnownInventory {
+        KnownInventory {
+            items: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn insert(&mut self, item: InvItem) {
+        if !self.items.insert(item) {
+            return;
+        }
+        self.order.push_back(item);
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            let oldest = self.order.pop_front().unwrap();
+            self.items.remove(&oldest);
+        }
+    }
+
+    fn contains(&self, item: &InvItem) -> bool {
+        self.items.contains(item)
+    }
+}
+
+/// An item we asked a peer for
+struct InFlight {
+    peer: PeerId,
+    sent: Instant,
+    /// Other peers that announced the item, to ask if `peer` doesn't deliver
+    fallbacks: VecDeque<PeerId>,
+}
+
+pub struct Relay {
+    known: HashMap<PeerId, KnownInventory>,
+    in_flight: HashMap<InvItem, InFlight>,
+}
+
+impl Relay {
+    pub fn new() -> Relay {
+        Relay {
+            known: HashMap::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: PeerId) {
+        self.known.insert(peer, KnownInventory::new());
+    }
+
+    /// Forget a peer; returns the items it was supposed to deliver that
+    /// should now be asked from another peer
+    pub fn remove_peer(&mut self, peer: PeerId) -> Vec<(PeerId, InvItem)> {
+        self.known.remove(&peer);
+        for req in self.in_flight.values_mut() {
+            req.fallbacks.retain(|&p| p != peer);
+        }
+
+        let lost: Vec<InvItem> = self.in_flight.iter()
+            .filter(|&(_, req)| req.peer == peer)
+            .map(|(item, _)| *item)
+            .collect();
+        lost.into_iter().filter_map(|item| self.retry(item)).collect()
+    }
+
+    /// Record that a peer has an item
+    pub fn mark_known(&mut self, peer: PeerId, item: InvItem) {
+        if let Some(known) = self.known.get_mut(&peer) {
+            known.insert(item);
+        }
+    }
+
+    pub fn is_known(&self, peer: PeerId, item: &InvItem) -> bool {
+        match self.known.get(&peer) {
+            Some(known) => known.contains(item),
+            None => false
+        }
+    }
+
+    /// A peer announced items; returns those to request from it. `have`
+    /// tells whether we already have an item.
+    pub fn announced<F: Fn(&InvItem) -> bool>(&mut self, peer: PeerId, items: &[InvItem], have: F) -> Vec<InvItem> {
+        let mut wanted = Vec::new();
+        for item in items {
+            self.mark_known(peer, *item);
+            if have(item) {
+                continue;
+            }
+
+            // Already asked someone else; keep this peer as a fallback
+            if let Some(req) = self.in_flight.get_mut(item) {
+                if req.peer != peer && !req.fallbacks.contains(&peer) {
+                    req.fallbacks.push_back(peer);
+                }
+                continue;
+            }
+
+            self.in_flight.insert(*item, InFlight {
+                peer: peer,
+                sent: Instant::now(),
+                fallbacks: VecDeque::new(),
+            });
+            wanted.push(*item);
+        }
+        wanted
+    }
+
+    /// An item arrived from a peer
+    pub fn received(&mut self, peer: PeerId, item: InvItem) {
+        self.mark_known(peer, item);
+        self.in_flight.remove(&item);
+    }
+
+    /// A peer could not deliver an item; returns who to ask next, if anyone
+    pub fn not_found(&mut self, peer: PeerId, item: InvItem) -> Option<(PeerId, InvItem)> {
+        match self.in_flight.get(&item) {
+            Some(req) if req.peer == peer => (),
+            _ => return None
+        }
+        self.retry(item)
+    }
+
+    /// Requests that timed out, re-assigned to fallback peers
+    pub fn expire(&mut self) -> Vec<(PeerId, InvItem)> {
+        let late: Vec<InvItem> = self.in_flight.iter()
+            .filter(|&(_, req)| req.sent.elapsed() >= GETDATA_TIMEOUT)
+            .map(|(item, _)| *item)
+            .collect();
+        late.into_iter().filter_map(|item| self.retry(item)).collect()
+    }
+
+    /// Hand an in-flight request over to the next fallback peer, or drop it
+    fn retry(&mut self, item: InvItem) -> Option<(PeerId, InvItem)> {
+        let next = match self.in_flight.get_mut(&item) {
+            Some(req) => req.fallbacks.pop_front(),
+            None => return None
+        };
+        match next {
+            Some(peer) => {
+                let req = self.in_flight.get_mut(&item).unwrap();
+                req.peer = peer;
+                req.sent = Instant::now();
+                Some((peer, item))
+            },
+            None => {
+                self.in_flight.remove(&item);
+                None
+            }
+        }
+    }
+
+    /// Peers a new item should be announced to, i.e. those not known to
+    /// have it already. They are marked as knowing it from now on.
+    pub fn announce(&mut self, item: InvItem) -> Vec<PeerId> {
+        let mut peers = Vec::new();
+        for (peer, known) in self.known.iter_mut() {
+            if !known.contains(&item) {
+                known.insert(item);
+                peers.push(*peer);
+            }
+        }
+        peers.sort();
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::TxHash;
+
+    fn item(b: u8) -> InvItem {
+        InvItem::Tx(TxHash::from_bytes(&[b; 32]))
+    }
+
+    #[test]
+    fn no_echo() {
+        let mut relay = Relay::new();
+        for peer in 0..3 {
+            relay.add_peer(peer);
+        }
+
+        // Peer 1 announces, we fetch from it and pass it on to everyone else
+        assert_eq!(relay.announced(1, &[item(7)], |_| false), vec!(item(7)));
+        relay.received(1, item(7));
+        assert_eq!(relay.announce(item(7)), vec!(0, 2));
+
+        // Nobody gets it twice
+        assert!(relay.announce(item(7)).is_empty());
+        assert!(relay.announced(2, &[item(7)], |_| true).is_empty());
+    }
+
+    #[test]
+    fn fallbacks() {
+        let mut relay = Relay::new();
+        for peer in 0..3 {
+            relay.add_peer(peer);
+        }
+
+        // Only the first announcer is asked
+        assert_eq!(relay.announced(0, &[item(1), item(2)], |_| false), vec!(item(1), item(2)));
+        assert!(relay.announced(1, &[item(1)], |_| false).is_empty());
+        assert!(relay.announced(2, &[item(1), item(2)], |_| false).is_empty());
+
+        // Fallbacks are asked in turn
+        assert_eq!(relay.not_found(0, item(1)), Some((1, item(1))));
+        assert_eq!(relay.not_found(0, item(1)), None);
+        let mut lost = relay.remove_peer(1);
+        lost.extend(relay.remove_peer(0));
+        assert_eq!(lost.len(), 2);
+        assert!(lost.contains(&(2, item(1))));
+        assert!(lost.contains(&(2, item(2))));
+
+        // Once out of fallbacks, the item is dropped and may be asked for again
+        assert_eq!(relay.not_found(2, item(1)), None);
+        assert_eq!(relay.announced(2, &[item(1)], |_| false), vec!(item(1)));
+    }
+}
@@ -0,0 +1,214 @@
+// Difficulty retargeting.
+//
+// The target of every block is fully determined by the headers before it, so
+// any node can check it. Networks choose one of:
+//
+// - `Periodic`: Bitcoin's rule. The target only changes every `interval`
+//   blocks, scaled by how long the last window actually took versus how long
+//   it should have taken. Like Bitcoin, the window spans `interval - 1` block
+//   gaps.
+// - `MovingAverage`: retarget every block, from the average target and the
+//   time taken over the last `window` blocks.
+// - `Fixed`: the target never changes (regression tests).
+//
+// Either way, the observed timespan is clamped so the target moves by at most
+// `max_adjust` times per adjustment, and it never gets easier than the limit.
+//
+use std::cmp;
+use pow;
+use pow::U256;
+use protocol::BlockHeader;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Periodic { interval: u64 },
+    MovingAverage { window: u64 },
+    Fixed,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetargetParams {
+    pub algorithm: Algorithm,
+    /// Desired seconds between blocks
+    pub spacing: u64,
+    /// Easiest target allowed, in compact form
+    pub pow_limit: u32,
+    /// Largest factor the target may change by in one adjustment
+    pub max_adjust: u64,
+}
+
+impl RetargetParams {
+    /// How many of the most recent headers `next_bits` needs
+    pub fn history_len(&self) -> usize {
+        match self.algorithm {
+            Algorithm::Periodic { interval } => interval as usize,
+            Algorithm::MovingAverage { window } => window as usize + 1,
+            Algorithm::Fixed => 1,
+        }
+    }
+}
+
+/// Target required of the block at `height`, in compact form.
+///
+/// `history` holds the headers right before it, oldest first; at least
+/// `params.history_len()` of them unless the chain is shorter than that.
+pub fn next_bits(params: &RetargetParams, height: u64, history: &[BlockHeader]) -> u32 {
+    let last = match history.last() {
+        Some(last) => last,
+        None => return params.pow_limit
+    };
+
+    match params.algorithm {
+        Algorithm::Fixed => params.pow_limit,
+        Algorithm::Periodic { interval } => {
+            if height % interval != 0 || history.len() < interval as usize {
+                return last.bits;
+            }
+            let first = &history[history.len() - interval as usize];
+            let timespan = last.timestamp.saturating_sub(first.timestamp);
+            let target = U256::from_target(&expand(last.bits));
+            adjust(params, target, timespan, interval * params.spacing)
+        },
+        Algorithm::MovingAverage { window } => {
+            let window = window as usize;
+            if history.len() < window + 1 {
+                return params.pow_limit;
+            }
+            let recent = &history[history.len() - window..];
+            let first = &history[history.len() - window - 1];
+            let timespan = last.timestamp.saturating_sub(first.timestamp);
+
+            // Average without overflowing: sum quotients and remainders apart
+            let mut quotients = U256::zero();
+            let mut remainders = 0u64;
+            for header in recent {
+                let (q, r) = U256::from_target(&expand(header.bits)).div_rem_u64(window as u64);
+                quotients = quotients.checked_add(&q).unwrap();
+                remainders += r;
+            }
+            let average = quotients.checked_add(&U256::from_u64(remainders / window as u64)).unwrap();
+            adjust(params, average, timespan, window as u64 * params.spacing)
+        }
+    }
+}
+
+/// Whether a header carries the target required at `height`
+pub fn check_bits(params: &RetargetParams, height: u64, history: &[BlockHeader], header: &BlockHeader) -> bool {
+    header.bits == next_bits(params, height, history)
+}
+
+/// Target, or the limit if the compact form is invalid
+fn expand(bits: u32) -> pow::Target {
+    pow::target_from_bits(bits).unwrap_or([0xff; 32])
+}
+
+/// Scale `target` by `actual / expected` time, clamped
+fn adjust(params: &RetargetParams, target: U256, actual: u64, expected: u64) -> u32 {
+    let actual = cmp::max(actual, expected / params.max_adjust);
+    let actual = cmp::min(actual, expected * params.max_adjust);
+
+    let limit = U256::from_target(&pow::target_from_bits(params.pow_limit).unwrap());
+    let adjusted = match target.checked_mul_u64(actual) {
+        Some(scaled) => scaled.div_rem_u64(expected).0,
+        None => limit
+    };
+    if adjusted > limit || adjusted.is_zero() {
+        return params.pow_limit;
+    }
+    pow::bits_from_target(&adjusted.to_target())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{BlockHash, TxHash};
+
+    fn bitcoin() -> RetargetParams {
+        RetargetParams {
+            algorithm: Algorithm::Periodic { interval: 2016 },
+            spacing: 600,
+            pow_limit: 0x1d00ffff,
+            max_adjust: 4,
+        }
+    }
+
+    /// `count` headers with the given bits, `spacing` seconds apart
+    fn headers(count: usize, start: u64, spacing: u64, bits: u32) -> Vec<BlockHeader> {
+        (0..count).map(|i| BlockHeader {
+            version: 1,
+            prev_hash: BlockHash::new(),
+            merkle_root: TxHash::new(),
+            timestamp: start + i as u64 * spacing,
+            bits: bits,
+            nonce: 0,
+        }).collect()
+    }
+
+    /// A periodic window that started and ended at the given times
+    fn window(first: u64, last: u64, bits: u32) -> Vec<BlockHeader> {
+        let mut history = headers(2016, first, 600, bits);
+        history.last_mut().unwrap().timestamp = last;
+        history
+    }
+
+    #[test]
+    fn periodic() {
+        // Test vectors from Bitcoin Core's pow_tests.cpp
+        let params = bitcoin();
+        let vectors = [
+            (32256, 1261130161, 1262152739, 0x1d00ffff, 0x1d00d86a),
+            (2016, 1231006505, 1233061996, 0x1d00ffff, 0x1d00ffff),
+            (68544, 1279008237, 1279297671, 0x1c05a3f4, 0x1c0168fd),
+            (46368, 1263163443, 1269211443, 0x1c387f6f, 0x1d00e1fd),
+        ];
+        for &(height, first, last, bits, expected) in vectors.iter() {
+            assert_eq!(next_bits(&params, height, &window(first, last, bits)), expected);
+        }
+
+        // Between retargets the target stays put
+        let history = window(1261130161, 1262152739, 0x1c05a3f4);
+        assert_eq!(next_bits(&params, 32257, &history), 0x1c05a3f4);
+        assert!(!check_bits(&params, 32256, &history, &headers(1, 0, 0, 0x1c0168fd)[0]));
+        assert_eq!(next_bits(&params, 0, &[]), 0x1d00ffff);
+    }
+
+    #[test]
+    fn moving_average() {
+        let params = RetargetParams {
+            algorithm: Algorithm::MovingAverage { window: 10 },
+            spacing: 60,
+            pow_limit: 0x1e00ffff,
+            max_adjust: 4,
+        };
+
+        // Not enough history yet
+        assert_eq!(next_bits(&params, 5, &headers(5, 0, 60, 0x1d00ffff)), 0x1e00ffff);
+
+        // On schedule, too fast, too slow, and way too slow
+        assert_eq!(next_bits(&params, 11, &headers(11, 0, 60, 0x1d00ffff)), 0x1d00ffff);
+        assert_eq!(next_bits(&params, 11, &headers(11, 0, 30, 0x1d00ffff)), 0x1c7fff80);
+        assert_eq!(next_bits(&params, 11, &headers(11, 0, 120, 0x1d00ffff)), 0x1d01fffe);
+        assert_eq!(next_bits(&params, 11, &headers(11, 0, 6000, 0x1d00ffff)), 0x1d03fffc);
+
+        // The target never gets easier than the limit
+        assert_eq!(next_bits(&params, 11, &headers(11, 0, 6000, 0x1e00ffff)), 0x1e00ffff);
+
+        // Targets are averaged over the window
+        let mut history = headers(11, 0, 60, 0x1d00ffff);
+        for header in history.iter_mut().skip(6) {
+            header.bits = 0x1d02fffd;
+        }
+        assert_eq!(next_bits(&params, 11, &history), 0x1d01fffe);
+    }
+
+    #[test]
+    fn fixed() {
+        let params = RetargetParams {
+            algorithm: Algorithm::Fixed,
+            spacing: 1,
+            pow_limit: pow::REGTEST_BITS,
+            max_adjust: 4,
+        };
+        assert_eq!(next_bits(&params, 100, &headers(1, 0, 1000, 0x1d00ffff)), pow::REGTEST_BITS);
+    }
+}
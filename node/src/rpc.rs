@@ -1,14 +1,18 @@
 use std::collections::HashMap;
+use std::sync::mpsc::Sender;
 use method::Method;
+use task::{Request, TaskResult};
 
 pub struct Server<'a> {
-    pub methods: HashMap<String, Box<Method + 'a>>,
+    pub methods: HashMap<String, Box<dyn Method + 'a>>,
+    node: Sender<Request>,
 }
 
 impl<'a> Server<'a> {
-    pub fn new() -> Server<'a> {
+    pub fn new(node: Sender<Request>) -> Server<'a> {
         Server {
             methods: HashMap::new(),
+            node: node,
         }
     }
 
@@ -22,4 +26,16 @@ impl<'a> Server<'a> {
     pub fn add_method<T: Method + 'a>(&mut self, m: T) {
         self.methods.insert(m.name().to_string(), Box::new(m));
     }
+
+    /// Hand a method over to the node and wait for its answer
+    pub fn call(&self, name: &str, params: &[String]) -> Option<TaskResult> {
+        let method = self.methods.get(name)?;
+        let task = match method.task(params) {
+            Some(task) => task,
+            None => return Some(TaskResult::Error { error: format!("invalid parameters for `{}`", name) })
+        };
+        let (req, reply) = Request::new(task);
+        self.node.send(req).ok()?;
+        reply.recv().ok()
+    }
 }
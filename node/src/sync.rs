@@ -0,0 +1,178 @@
+// Block download.
+//
+// Sync is headers-first: every peer is asked for the headers following our
+// best header (`getheaders`), which are cheap to check and tell us which
+// chain has the most work. Block bodies along that chain are then fetched
+// (`getblocks`) from several peers at once, at most `WINDOW` blocks ahead of
+// our tip and `MAX_IN_FLIGHT_PER_PEER` per peer; the peers with the lowest
+// ping latency get the earliest blocks. A peer that doesn't deliver in time
+// is dropped, and its blocks are asked from others.
+//
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use peer::PeerId;
+use protocol::BlockHash;
+
+/// How far ahead of the tip blocks are downloaded
+pub const WINDOW: u64 = 1024;
+
+/// Most blocks asked from a single peer at a time
+pub const MAX_IN_FLIGHT_PER_PEER: usize = 16;
+
+/// How long a peer has to deliver a block we asked for
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a peer has to answer `getheaders`
+pub const HEADERS_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Default)]
+struct SyncPeer {
+    /// Blocks asked from the peer
+    in_flight: usize,
+    /// When we asked the peer for headers, if it has yet to answer
+    headers_sent: Option<Instant>,
+}
+
+pub struct Sync {
+    peers: HashMap<PeerId, SyncPeer>,
+    /// Blocks asked for, from whom and when
+    in_flight: HashMap<BlockHash, (PeerId, Instant)>,
+}
+
+impl Sync {
+    pub fn new() -> Sync {
+        Sync {
+            peers: HashMap::new(),
+            in_flight: HashMap::new(),
+        }
+    }
+
+    pub fn add_peer(&mut self, peer: PeerId) {
+        self.peers.insert(peer, SyncPeer::default());
+    }
+
+    /// Forget a peer; the blocks it was to deliver can be asked from others
+    pub fn remove_peer(&mut self, peer: PeerId) {
+        self.peers.remove(&peer);
+        self.in_flight.retain(|_, &mut (p, _)| p != peer);
+    }
+
+    /// Blocks being downloaded
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// We just sent `getheaders` to the peer
+    pub fn headers_requested(&mut self, peer: PeerId) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.headers_sent = Some(Instant::now());
+        }
+    }
+
+    /// The peer answered `getheaders`
+    pub fn headers_received(&mut self, peer: PeerId) {
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.headers_sent = None;
+        }
+    }
+
+    /// A block arrived; returns the peer we asked it from, if any
+    pub fn block_received(&mut self, hash: &BlockHash) -> Option<PeerId> {
+        let (peer, _) = self.in_flight.remove(hash)?;
+        if let Some(state) = self.peers.get_mut(&peer) {
+            state.in_flight -= 1;
+        }
+        Some(peer)
+    }
+
+    /// Spread `missing` blocks, lowest first, over peers with room left,
+    /// filling the peers in `fastest` first, in that order; returns what to
+    /// ask from whom, in the same order
+    pub fn schedule(&mut self, missing: &[BlockHash], fastest: &[PeerId]) -> Vec<(PeerId, Vec<BlockHash>)> {
+        // Peers missing from `fastest` come last
+        let mut peers: Vec<PeerId> = fastest.iter().cloned()
+            .filter(|peer| self.peers.contains_key(peer))
+            .collect();
+        let mut others: Vec<PeerId> = self.peers.keys().cloned()
+            .filter(|peer| !fastest.contains(peer))
+            .collect();
+        others.sort();
+        peers.extend(others);
+
+        let mut requests: HashMap<PeerId, Vec<BlockHash>> = HashMap::new();
+        let now = Instant::now();
+        for hash in missing {
+            if self.in_flight.contains_key(hash) {
+                continue;
+            }
+            let peer = match peers.iter().find(|peer| self.peers[peer].in_flight < MAX_IN_FLIGHT_PER_PEER) {
+                Some(&peer) => peer,
+                None => break
+            };
+            self.peers.get_mut(&peer).unwrap().in_flight += 1;
+            self.in_flight.insert(*hash, (peer, now));
+            requests.entry(peer).or_default().push(*hash);
+        }
+
+        peers.into_iter()
+            .filter_map(|peer| requests.remove(&peer).map(|hashes| (peer, hashes)))
+            .collect()
+    }
+
+    /// Peers that let a request time out; they should be dropped
+    pub fn stalled(&self) -> Vec<PeerId> {
+        let mut stalled: Vec<PeerId> = self.in_flight.values()
+            .filter(|&&(_, sent)| sent.elapsed() >= BLOCK_TIMEOUT)
+            .map(|&(peer, _)| peer)
+            .collect();
+        stalled.extend(self.peers.iter()
+            .filter(|&(_, state)| state.headers_sent.map_or(false, |sent| sent.elapsed() >= HEADERS_TIMEOUT))
+            .map(|(&peer, _)| peer));
+        stalled.sort();
+        stalled.dedup();
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(n: u8) -> Vec<BlockHash> {
+        (0..n).map(|i| BlockHash::from_bytes(&[i; 32])).collect()
+    }
+
+    #[test]
+    fn schedule() {
+        let mut sync = Sync::new();
+        let missing = hashes(3 * MAX_IN_FLIGHT_PER_PEER as u8);
+        assert!(sync.schedule(&missing, &[]).is_empty());
+
+        // The fastest peer gets the earliest blocks, up to the per-peer limit;
+        // peers left out of the order come last
+        sync.add_peer(1);
+        sync.add_peer(2);
+        sync.add_peer(3);
+        let requests = sync.schedule(&missing, &[2, 1]);
+        let limit = MAX_IN_FLIGHT_PER_PEER;
+        assert_eq!(requests, vec![
+            (2, missing[..limit].to_vec()),
+            (1, missing[limit..2 * limit].to_vec()),
+            (3, missing[2 * limit..].to_vec()),
+        ]);
+        assert_eq!(sync.in_flight(), missing.len());
+
+        // Nothing is asked twice; delivered blocks make room
+        let more = hashes(missing.len() as u8 + 10);
+        assert!(sync.schedule(&missing, &[2, 1]).is_empty());
+        assert_eq!(sync.block_received(&missing[0]), Some(2));
+        assert_eq!(sync.block_received(&missing[0]), None);
+        assert_eq!(sync.schedule(&more[1..], &[1, 2]), vec![(2, vec![more[missing.len()]])]);
+
+        // A dropped peer's blocks go to the others
+        sync.remove_peer(2);
+        sync.block_received(&missing[limit]);
+        assert_eq!(sync.schedule(&more[1..], &[2, 1]), vec![(1, vec![more[1]])]);
+        assert!(sync.stalled().is_empty());
+    }
+}
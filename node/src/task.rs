@@ -1,15 +1,7 @@
-
-pub trait Task {
-    fn name() -> String;
-    fn args() -> 
-}
-
-
-pub struct DumpPrivKey;
-
-impl Task for DumpPrivKey {
-    fn name() -> String { "dumpprivkey" };
-}
+use std::sync::mpsc::{channel, Sender, Receiver};
+use mempool::FeeRate;
+use peer_manager::PeerInfo;
+use protocol::TxHash;
 
 /// RPC-to-Node communication protocol.
 pub enum Task {
@@ -26,5 +18,95 @@ pub enum Task {
     ///     id: Integer
     ///     addr: String
     ///     conntime: Integer
-    GetPeerInfo
+    ///     ...
+    GetPeerInfo,
+    /// Input: none.
+    /// Output: object.
+    ///     mining: Boolean
+    ///     threads: Integer
+    ///     height: Integer
+    StartMining,
+    /// Input: none.
+    /// Output: same as `StartMining`.
+    StopMining,
+    /// Input: none.
+    /// Output: object.
+    ///     headers: Integer
+    ///     blocks: Integer
+    ///     in_flight: Integer
+    GetSyncInfo,
+    /// Input: none.
+    /// Output: object.
+    ///     height: Integer
+    ///     prev_hash: String
+    ///     timestamp: Integer
+    ///     bits: Integer
+    ///     fees: Integer
+    ///     transactions: Integer
+    ///     block: <block ready to mine, MessagePack-encoded, base64>
+    GetBlockTemplate,
+    /// Input: hash of one of the account's mempool transactions, base58;
+    /// fee rate to pay at least, per 1000 bytes.
+    /// Output: object.
+    ///     tx: String
+    ///     fee: Integer
+    BumpFee { tx: TxHash, fee_rate: FeeRate }
+}
+
+/// Node's answer to a `Task`, one variant per task.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum TaskResult {
+    PrivKey(String),
+    NetworkInfo {
+        num_nodes: usize
+    },
+    PeerInfo(Vec<PeerInfo>),
+    MiningInfo {
+        mining: bool,
+        threads: usize,
+        height: u64
+    },
+    SyncInfo {
+        /// Height of the best header
+        headers: u64,
+        /// Height of the active chain
+        blocks: u64,
+        /// Blocks being downloaded
+        in_flight: usize
+    },
+    BlockTemplate {
+        height: u64,
+        /// Hash of the tip the block builds on, base58
+        prev_hash: String,
+        timestamp: u64,
+        bits: u32,
+        /// Fees paid to the miner on top of the subsidy
+        fees: u64,
+        /// Transactions in the block, the coinbase included
+        transactions: usize,
+        block: String
+    },
+    Bumped {
+        /// Hash of the replacement, base58
+        tx: String,
+        fee: u64
+    },
+    /// The task failed
+    Error {
+        error: String
+    }
+}
+
+/// A task, along with the channel the node answers on.
+pub struct Request {
+    pub task: Task,
+    pub reply: Sender<TaskResult>
+}
+
+impl Request {
+    pub fn new(task: Task) -> (Request, Receiver<TaskResult>) {
+        let (tx, rx) = channel();
+        (Request { task: task, reply: tx }, rx)
+    }
 }
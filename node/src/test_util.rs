@@ -0,0 +1,50 @@
+// Helpers shared by the tests.
+//
+// Keys are derived from a one-byte seed, so a test can name as many owners as
+// it needs. Transactions are signed against the amounts of the outputs they
+// spend, which callers pass along with each input.
+//
+use ed25519_dalek::{Keypair, PublicKey, SecretKey};
+use sha2::Sha512;
+use protocol::{Address, Block, BlockHash, Transaction, TxHash};
+use utxo::UtxoSet;
+
+/// Key derived from `seed`
+pub fn keypair(seed: u8) -> Keypair {
+    let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+    let public = PublicKey::from_secret::<Sha512>(&secret);
+    Keypair { secret: secret, public: public }
+}
+
+/// Address of `seed`'s key
+pub fn owner(seed: u8) -> Address {
+    Address::from_public_key(&keypair(seed).public)
+}
+
+/// UTXO set holding outputs of the given amounts, owned by `seed`'s key,
+/// along with the hash of the transaction that created them
+pub fn funded(seed: u8, amounts: &[u64]) -> (UtxoSet, TxHash) {
+    let mut funding = Transaction::new();
+    for &amount in amounts {
+        funding.add_output(amount, owner(seed));
+    }
+    let hash = funding.hash();
+    let mut set = UtxoSet::new();
+    set.connect_block(&Block::new(BlockHash::new(), 0, 0, vec![funding]), 1).unwrap();
+    (set, hash)
+}
+
+/// Transaction by `seed`'s key, paying back to the same key. Inputs come
+/// with the amount of the output they spend.
+pub fn spend(seed: u8, inputs: &[(TxHash, u8, u64)], amounts: &[u64]) -> Transaction {
+    let mut tx = Transaction::new();
+    for &(hash, index, _) in inputs {
+        tx.add_input(hash, index, keypair(seed).public);
+    }
+    for &amount in amounts {
+        tx.add_output(amount, owner(seed));
+    }
+    let spent: Vec<u64> = inputs.iter().map(|&(_, _, amount)| amount).collect();
+    tx.sign(keypair(seed), &spent);
+    tx
+}
@@ -0,0 +1,315 @@
+// Unspent transaction outputs.
+//
+// The UTXO set holds every output that can still be spent, keyed by the
+// transaction that created it and its index there. Connecting a block spends
+// its inputs and adds its outputs; the outputs it spent are saved as undo
+// data, so disconnecting the block during a reorg restores the set exactly.
+//
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use protocol::{Block, Output, Transaction, TxHash};
+
+/// Most outputs a transaction can have, as `OutPoint` indices are single bytes
+pub const MAX_OUTPUTS: usize = u8::max_value() as usize + 1;
+
+/// Reference to a transaction output
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub tx: TxHash,
+    pub index: u8,
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}:{}", self.tx, self.index)
+    }
+}
+
+/// An unspent output, along with where it was created
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct UtxoEntry {
+    pub output: Output,
+    /// Height of the block that created the output
+    pub height: u64,
+    /// Whether a coinbase created the output, c.f. `coinbase_maturity`
+    pub coinbase: bool,
+}
+
+/// Read access to a set of unspent outputs
+pub trait UtxoView {
+    fn get(&self, outpoint: &OutPoint) -> Option<UtxoEntry>;
+
+    fn contains(&self, outpoint: &OutPoint) -> bool {
+        self.get(outpoint).is_some()
+    }
+}
+
+/// What it takes to disconnect a block: the outputs it spent, in spending order
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<(OutPoint, UtxoEntry)>,
+}
+
+/// Failure to connect or disconnect a block; the set is left untouched
+#[derive(Debug, PartialEq)]
+pub enum UtxoError {
+    /// An input spends an output that does not exist or is already spent
+    MissingInput(OutPoint),
+    /// A transaction would create an output that is already unspent
+    Overwrite(OutPoint),
+    /// A transaction has more than `MAX_OUTPUTS` outputs
+    TooManyOutputs(TxHash),
+    /// Undo data does not match the block being disconnected
+    BadUndo,
+}
+
+impl fmt::Display for UtxoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &UtxoError::MissingInput(ref o) => write!(f, "input {} is missing or already spent", o),
+            &UtxoError::Overwrite(ref o) => write!(f, "output {} already exists", o),
+            &UtxoError::TooManyOutputs(ref h) => write!(f, "transaction {:?} has too many outputs", h),
+            &UtxoError::BadUndo => write!(f, "undo data does not match the block"),
+        }
+    }
+}
+
+impl Error for UtxoError {
+    fn description(&self) -> &str {
+        "UTXO set update error"
+    }
+}
+
+/// Pending changes on top of a UTXO set, e.g. those of a block being validated
+pub struct UtxoOverlay<'a, V: UtxoView + 'a> {
+    base: &'a V,
+    added: HashMap<OutPoint, UtxoEntry>,
+    spent: HashSet<OutPoint>,
+}
+
+impl<'a, V: UtxoView> UtxoOverlay<'a, V> {
+    pub fn new(base: &'a V) -> UtxoOverlay<'a, V> {
+        UtxoOverlay {
+            base: base,
+            added: HashMap::new(),
+            spent: HashSet::new(),
+        }
+    }
+
+    /// Spend the transaction's inputs and add its outputs; the caller checks
+    /// the inputs exist, and that there are at most `MAX_OUTPUTS` outputs,
+    /// beforehand
+    pub fn apply(&mut self, tx: &Transaction, height: u64) {
+        for input in tx.inputs() {
+            let outpoint = OutPoint { tx: input.tx, index: input.index };
+            if self.added.remove(&outpoint).is_none() {
+                self.spent.insert(outpoint);
+            }
+        }
+        let hash = tx.hash();
+        for (index, output) in (0..=u8::max_value()).zip(tx.outputs()) {
+            let entry = UtxoEntry { output: *output, height: height, coinbase: tx.is_coinbase() };
+            self.added.insert(OutPoint { tx: hash, index: index }, entry);
+        }
+    }
+}
+
+impl<'a, V: UtxoView> UtxoView for UtxoOverlay<'a, V> {
+    fn get(&self, outpoint: &OutPoint) -> Option<UtxoEntry> {
+        if self.spent.contains(outpoint) {
+            return None;
+        }
+        match self.added.get(outpoint) {
+            Some(entry) => Some(*entry),
+            None => self.base.get(outpoint)
+        }
+    }
+}
+
+pub struct UtxoSet {
+    entries: HashMap<OutPoint, UtxoEntry>,
+}
+
+impl UtxoView for UtxoSet {
+    fn get(&self, outpoint: &OutPoint) -> Option<UtxoEntry> {
+        self.entries.get(outpoint).cloned()
+    }
+}
+
+impl UtxoSet {
+    pub fn new() -> UtxoSet {
+        UtxoSet {
+            entries: HashMap::new()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Spend the block's inputs and add its outputs. Transactions may spend
+    /// outputs created earlier in the same block.
+    pub fn connect_block(&mut self, block: &Block, height: u64) -> Result<BlockUndo, UtxoError> {
+        let mut undo = BlockUndo::default();
+        let mut added = Vec::new();
+
+        for tx in &block.txs {
+            if let Err(e) = self.connect_tx(tx, height, &mut undo, &mut added) {
+                // Leave the set as we found it. Outputs both created and
+                // spent in the block come back with the spent ones, then go
+                for &(outpoint, entry) in undo.spent.iter().rev() {
+                    self.entries.insert(outpoint, entry);
+                }
+                for outpoint in &added {
+                    self.entries.remove(outpoint);
+                }
+                return Err(e);
+            }
+        }
+        Ok(undo)
+    }
+
+    fn connect_tx(&mut self, tx: &Transaction, height: u64, undo: &mut BlockUndo, added: &mut Vec<OutPoint>) -> Result<(), UtxoError> {
+        let hash = tx.hash();
+        if tx.outputs().len() > MAX_OUTPUTS {
+            return Err(UtxoError::TooManyOutputs(hash));
+        }
+        for input in tx.inputs() {
+            let outpoint = OutPoint { tx: input.tx, index: input.index };
+            match self.entries.remove(&outpoint) {
+                Some(entry) => undo.spent.push((outpoint, entry)),
+                None => return Err(UtxoError::MissingInput(outpoint))
+            }
+        }
+
+        for (index, output) in (0..=u8::max_value()).zip(tx.outputs()) {
+            let outpoint = OutPoint { tx: hash, index: index };
+            if self.entries.contains_key(&outpoint) {
+                return Err(UtxoError::Overwrite(outpoint));
+            }
+            self.entries.insert(outpoint, UtxoEntry { output: *output, height: height, coinbase: tx.is_coinbase() });
+            added.push(outpoint);
+        }
+        Ok(())
+    }
+
+    /// Undo `connect_block`: remove the block's outputs and give back the
+    /// ones it spent. Blocks must be disconnected tip first.
+    pub fn disconnect_block(&mut self, block: &Block, undo: &BlockUndo) -> Result<(), UtxoError> {
+        // Check everything up front, so a mismatch changes nothing
+        let inputs: usize = block.txs.iter().map(|tx| tx.inputs().len()).sum();
+        if inputs != undo.spent.len() {
+            return Err(UtxoError::BadUndo);
+        }
+        let mut spent = undo.spent.iter();
+        for input in block.txs.iter().flat_map(|tx| tx.inputs()) {
+            let &(outpoint, _) = spent.next().unwrap();
+            if outpoint.tx != input.tx || outpoint.index != input.index {
+                return Err(UtxoError::BadUndo);
+            }
+        }
+
+        // Walk the block backwards, so outputs both created and spent in it
+        // are restored, then removed again
+        let mut spent = undo.spent.iter().rev();
+        for tx in block.txs.iter().rev() {
+            let hash = tx.hash();
+            for (index, _) in (0..=u8::max_value()).zip(tx.outputs()) {
+                self.entries.remove(&OutPoint { tx: hash, index: index });
+            }
+            for _ in tx.inputs() {
+                let &(outpoint, entry) = spent.next().unwrap();
+                self.entries.insert(outpoint, entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{Address, BlockHash};
+    use test_util::keypair;
+
+    fn tx(inputs: &[(TxHash, u8)], amounts: &[u64]) -> Transaction {
+        let owner = keypair(1).public;
+        let mut tx = Transaction::new();
+        for &(hash, index) in inputs {
+            tx.add_input(hash, index, owner);
+        }
+        for &amount in amounts {
+            tx.add_output(amount, Address::new());
+        }
+        tx
+    }
+
+    fn block(txs: Vec<Transaction>) -> Block {
+        Block::new(BlockHash::new(), 0, 0, txs)
+    }
+
+    fn outpoints(set: &UtxoSet) -> Vec<(OutPoint, u64, u64)> {
+        let mut all: Vec<_> = set.entries.iter()
+            .map(|(o, e)| (*o, e.output.amount, e.height))
+            .collect();
+        all.sort_by_key(|&(o, amount, _)| (o.tx.as_bytes().to_vec(), o.index, amount));
+        all
+    }
+
+    #[test]
+    fn connect_disconnect() {
+        let mut set = UtxoSet::new();
+        let funding = tx(&[(TxHash::new(), 0)], &[]);
+        let first = tx(&[], &[10, 20]);
+        let first_hash = first.hash();
+        set.connect_block(&block(vec![first]), 1).unwrap();
+        assert_eq!(set.len(), 2);
+        let before = outpoints(&set);
+
+        // Spends one old output, and one created in the same block
+        let a = tx(&[(first_hash, 0)], &[4, 6]);
+        let b = tx(&[(a.hash(), 1)], &[5]);
+        let b_hash = b.hash();
+        let second = block(vec![a, b]);
+        let undo = set.connect_block(&second, 2).unwrap();
+        assert_eq!(undo.spent.len(), 2);
+        assert_eq!(set.len(), 3);
+        assert!(!set.contains(&OutPoint { tx: first_hash, index: 0 }));
+        assert_eq!(set.get(&OutPoint { tx: b_hash, index: 0 }).unwrap().height, 2);
+
+        // Undo data must match
+        assert_eq!(set.disconnect_block(&block(vec![funding]), &undo), Err(UtxoError::BadUndo));
+        set.disconnect_block(&second, &undo).unwrap();
+        assert_eq!(outpoints(&set), before);
+    }
+
+    #[test]
+    fn atomic_failure() {
+        let mut set = UtxoSet::new();
+        let first = tx(&[], &[10]);
+        let first_hash = first.hash();
+        set.connect_block(&block(vec![first]), 1).unwrap();
+        let before = outpoints(&set);
+
+        // Double spend in the last transaction
+        let a = tx(&[(first_hash, 0)], &[1]);
+        let b = tx(&[(a.hash(), 0)], &[1]);
+        let c = tx(&[(first_hash, 0)], &[2]);
+        assert_eq!(set.connect_block(&block(vec![a, b, c]), 2).err(),
+                   Some(UtxoError::MissingInput(OutPoint { tx: first_hash, index: 0 })));
+        assert_eq!(outpoints(&set), before);
+
+        // Same transaction twice
+        let again = tx(&[], &[10]);
+        assert_eq!(set.connect_block(&block(vec![again]), 2).err(),
+                   Some(UtxoError::Overwrite(OutPoint { tx: first_hash, index: 0 })));
+        assert_eq!(outpoints(&set), before);
+
+        // Output indices must fit in a byte
+        let wide = tx(&[], &[1; MAX_OUTPUTS + 1]);
+        let wide_hash = wide.hash();
+        assert_eq!(set.connect_block(&block(vec![wide]), 2).err(), Some(UtxoError::TooManyOutputs(wide_hash)));
+        assert_eq!(outpoints(&set), before);
+    }
+}
@@ -0,0 +1,578 @@
+// Transaction validation.
+//
+// Checks are split in two: `check_tx` looks at the transaction alone, and
+// `check_tx_inputs` checks it against the outputs it spends. A transaction is
+// valid if both pass; c.f.
+// https://en.bitcoin.it/wiki/Protocol_rules#.22tx.22_messages
+//
+// Coinbase transactions spend nothing and are only valid as the first
+// transaction of a block, minting at most the block subsidy plus the fees of
+// the other transactions; `check_block_txs` checks those rules.
+//
+// Blocks go through two stages as well: `check_block` needs nothing but the
+// block and the time, and `check_block_context` checks it against the chain
+// it builds on, transactions included. The header-only parts of both are
+// available on their own, for headers received ahead of their blocks. Every
+// failure tells how much the peer that sent the block is to blame, c.f.
+// `BlockError::penalty`.
+//
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use rmps;
+use sha2::Sha512;
+use chainparams::ChainParams;
+use pow;
+use protocol::{Address, Block, BlockHash, BlockHeader, Transaction, TxHash};
+use retarget;
+use utxo::{OutPoint, UtxoError, UtxoOverlay, UtxoView, MAX_OUTPUTS};
+
+/// Largest encoded block size, in bytes
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// How far in the future a block's timestamp may be, in seconds
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+/// Blocks the median time past is taken over
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Penalty for a block or transaction that can't be valid, whoever sends it
+pub const PENALTY_INVALID: u32 = 100;
+
+/// Why a transaction is invalid
+#[derive(Debug, PartialEq)]
+pub enum TxError {
+    NoInputs,
+    NoOutputs,
+    /// More outputs than inputs can refer to, c.f. `utxo::MAX_OUTPUTS`
+    TooManyOutputs(usize),
+    /// Coinbase with inputs
+    MalformedCoinbase,
+    /// Coinbase where only regular transactions are allowed
+    UnexpectedCoinbase,
+    /// The same output is spent twice
+    DuplicateInput(OutPoint),
+    /// Output amounts add up to more than fits in a `u64`
+    OutputOverflow,
+    /// Spent output does not exist or is already spent
+    MissingInput(OutPoint),
+    /// Spent output is not owned by the input's key
+    WrongOwner(OutPoint),
+    /// Spent coinbase output is not buried deep enough yet
+    ImmatureCoinbase(OutPoint),
+    /// Input at this index has an invalid signature hash type, or `SINGLE`
+    /// with no output at its index
+    BadSigHashType(usize),
+    /// Signature of the input at this index does not verify
+    BadSignature(usize),
+    /// Input amounts add up to more than fits in a `u64`
+    InputOverflow,
+    /// Outputs spend more than the inputs provide
+    InsufficientFunds { inputs: u64, outputs: u64 },
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &TxError::NoInputs => write!(f, "transaction has no inputs"),
+            &TxError::NoOutputs => write!(f, "transaction has no outputs"),
+            &TxError::TooManyOutputs(n) => write!(f, "transaction has {} outputs, more than {}", n, MAX_OUTPUTS),
+            &TxError::MalformedCoinbase => write!(f, "coinbase has inputs"),
+            &TxError::UnexpectedCoinbase => write!(f, "unexpected coinbase"),
+            &TxError::DuplicateInput(ref o) => write!(f, "output {} is spent twice", o),
+            &TxError::OutputOverflow => write!(f, "output total overflows"),
+            &TxError::MissingInput(ref o) => write!(f, "input {} is missing or already spent", o),
+            &TxError::WrongOwner(ref o) => write!(f, "output {} is not owned by the spending key", o),
+            &TxError::ImmatureCoinbase(ref o) => write!(f, "coinbase output {} is not mature yet", o),
+            &TxError::BadSigHashType(i) => write!(f, "bad signature hash type on input {}", i),
+            &TxError::BadSignature(i) => write!(f, "bad signature on input {}", i),
+            &TxError::InputOverflow => write!(f, "input total overflows"),
+            &TxError::InsufficientFunds { inputs, outputs } =>
+                write!(f, "outputs spend {} but inputs only provide {}", outputs, inputs),
+        }
+    }
+}
+
+impl Error for TxError {
+    fn description(&self) -> &str {
+        "invalid transaction"
+    }
+}
+
+/// Context-free checks; returns the output total
+pub fn check_tx(tx: &Transaction) -> Result<u64, TxError> {
+    if tx.is_coinbase() {
+        if !tx.inputs().is_empty() {
+            return Err(TxError::MalformedCoinbase);
+        }
+    } else {
+        if tx.inputs().is_empty() {
+            return Err(TxError::NoInputs);
+        }
+    }
+    if tx.outputs().is_empty() {
+        return Err(TxError::NoOutputs);
+    }
+    if tx.outputs().len() > MAX_OUTPUTS {
+        return Err(TxError::TooManyOutputs(tx.outputs().len()));
+    }
+
+    let mut seen = HashSet::new();
+    for input in tx.inputs() {
+        let outpoint = OutPoint { tx: input.tx, index: input.index };
+        if !seen.insert(outpoint) {
+            return Err(TxError::DuplicateInput(outpoint));
+        }
+    }
+
+    tx.outputs().iter()
+        .try_fold(0u64, |sum, output| sum.checked_add(output.amount))
+        .ok_or(TxError::OutputOverflow)
+}
+
+/// Check a regular transaction against the outputs it spends, were it
+/// included in a block at `height`; returns the fee
+pub fn check_tx_inputs<V: UtxoView>(tx: &Transaction, view: &V, height: u64, params: &ChainParams) -> Result<u64, TxError> {
+    if tx.is_coinbase() {
+        return Err(TxError::UnexpectedCoinbase);
+    }
+    let outputs = check_tx(tx)?;
+
+    let mut inputs = 0u64;
+    let mut amounts = Vec::with_capacity(tx.inputs().len());
+    for input in tx.inputs() {
+        let outpoint = OutPoint { tx: input.tx, index: input.index };
+        let entry = match view.get(&outpoint) {
+            Some(entry) => entry,
+            None => return Err(TxError::MissingInput(outpoint))
+        };
+        if entry.output.creditor != Address::from_public_key(&input.owner) {
+            return Err(TxError::WrongOwner(outpoint));
+        }
+        if entry.coinbase && height < entry.height + params.coinbase_maturity {
+            return Err(TxError::ImmatureCoinbase(outpoint));
+        }
+        inputs = inputs.checked_add(entry.output.amount).ok_or(TxError::InputOverflow)?;
+        amounts.push(entry.output.amount);
+    }
+
+    // Signatures commit to the amounts spent by every input
+    for (i, input) in tx.inputs().iter().enumerate() {
+        let hash = match tx.signature_hash(i, &amounts) {
+            Some(hash) => hash,
+            None => return Err(TxError::BadSigHashType(i))
+        };
+        if input.owner.verify::<Sha512>(hash.as_bytes(), &input.signature).is_err() {
+            return Err(TxError::BadSignature(i));
+        }
+    }
+
+    if inputs < outputs {
+        return Err(TxError::InsufficientFunds { inputs: inputs, outputs: outputs });
+    }
+    Ok(inputs - outputs)
+}
+
+/// Why a block is invalid
+#[derive(Debug, PartialEq)]
+pub enum BlockError {
+    /// Encoded block is larger than `MAX_BLOCK_SIZE`
+    TooLarge(usize),
+    /// First transaction is not a coinbase
+    NoCoinbase,
+    /// Coinbase at this index, past the first transaction
+    ExtraCoinbase(usize),
+    /// Same transaction twice
+    DuplicateTx(TxHash),
+    /// Header does not commit to the block's transactions
+    BadMerkleRoot,
+    /// Hash does not meet the header's target
+    BadProofOfWork,
+    /// Timestamp is more than `MAX_FUTURE_BLOCK_TIME` ahead of ours
+    TimeTooNew(u64),
+    /// Parent is not the block we build on
+    UnknownParent(BlockHash),
+    /// Builds on this block, which is invalid
+    InvalidAncestor(BlockHash),
+    /// Timestamp is not after the median time past
+    TimeTooOld { median: u64, timestamp: u64 },
+    /// Target is not the one retargeting requires
+    BadTarget { expected: u32, actual: u32 },
+    /// Coinbase commits to another height than the block's
+    CoinbaseHeight { expected: u64, actual: u64 },
+    /// Coinbase mints more than the subsidy plus fees
+    CoinbaseOverpays { allowed: u64, actual: u64 },
+    /// Transaction at this index is invalid
+    Tx(usize, TxError),
+    /// Fees add up to more than fits in a `u64`
+    FeeOverflow,
+    /// Block could not be connected to the UTXO set
+    Connect(UtxoError),
+}
+
+impl BlockError {
+    /// Misbehavior score of the peer that sent the block. Failures that
+    /// may be our own fault, like a clock off or a missing parent, cost
+    /// nothing.
+    pub fn penalty(&self) -> u32 {
+        match self {
+            &BlockError::TimeTooNew(_) | &BlockError::UnknownParent(_) => 0,
+            _ => PENALTY_INVALID
+        }
+    }
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &BlockError::TooLarge(size) => write!(f, "block of {} bytes exceeds the {} bytes limit", size, MAX_BLOCK_SIZE),
+            &BlockError::NoCoinbase => write!(f, "first transaction is not a coinbase"),
+            &BlockError::ExtraCoinbase(i) => write!(f, "transaction {} is a coinbase", i),
+            &BlockError::DuplicateTx(ref hash) => write!(f, "transaction {:?} is included twice", hash),
+            &BlockError::BadMerkleRoot => write!(f, "merkle root does not match the transactions"),
+            &BlockError::BadProofOfWork => write!(f, "hash does not meet the target"),
+            &BlockError::TimeTooNew(timestamp) => write!(f, "timestamp {} is too far in the future", timestamp),
+            &BlockError::UnknownParent(ref hash) => write!(f, "parent {:?} is unknown", hash),
+            &BlockError::InvalidAncestor(ref hash) => write!(f, "builds on invalid block {:?}", hash),
+            &BlockError::TimeTooOld { median, timestamp } =>
+                write!(f, "timestamp {} is not after the median time past {}", timestamp, median),
+            &BlockError::BadTarget { expected, actual } =>
+                write!(f, "target {:08x} should be {:08x}", actual, expected),
+            &BlockError::CoinbaseHeight { expected, actual } =>
+                write!(f, "coinbase is for height {} instead of {}", actual, expected),
+            &BlockError::CoinbaseOverpays { allowed, actual } =>
+                write!(f, "coinbase pays {} but only {} is allowed", actual, allowed),
+            &BlockError::Tx(i, ref e) => write!(f, "transaction {}: {}", i, e),
+            &BlockError::FeeOverflow => write!(f, "fee total overflows"),
+            &BlockError::Connect(ref e) => write!(f, "could not connect: {}", e),
+        }
+    }
+}
+
+impl Error for BlockError {
+    fn description(&self) -> &str {
+        "invalid block"
+    }
+}
+
+/// Context-free checks, `now` being the current Unix time
+pub fn check_block(block: &Block, now: u64) -> Result<(), BlockError> {
+    let size = rmps::to_vec(block).map(|buf| buf.len()).unwrap_or(usize::max_value());
+    if size > MAX_BLOCK_SIZE {
+        return Err(BlockError::TooLarge(size));
+    }
+
+    match block.txs.first() {
+        Some(tx) if tx.is_coinbase() => (),
+        _ => return Err(BlockError::NoCoinbase)
+    }
+    let mut seen = HashSet::new();
+    for (i, tx) in block.txs.iter().enumerate() {
+        if i > 0 && tx.is_coinbase() {
+            return Err(BlockError::ExtraCoinbase(i));
+        }
+        let hash = tx.hash();
+        if !seen.insert(hash) {
+            return Err(BlockError::DuplicateTx(hash));
+        }
+    }
+    if block.header.merkle_root != block.compute_merkle_root() {
+        return Err(BlockError::BadMerkleRoot);
+    }
+    check_header(&block.header, now)
+}
+
+/// Context-free checks of a header alone
+pub fn check_header(header: &BlockHeader, now: u64) -> Result<(), BlockError> {
+    if !pow::check_proof(&header.hash(), header.bits) {
+        return Err(BlockError::BadProofOfWork);
+    }
+    if header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+        return Err(BlockError::TimeTooNew(header.timestamp));
+    }
+    Ok(())
+}
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` headers, 0 if there are none
+pub fn median_time_past(history: &[BlockHeader]) -> u64 {
+    let start = history.len().saturating_sub(MEDIAN_TIME_SPAN);
+    let mut times: Vec<u64> = history[start..].iter().map(|h| h.timestamp).collect();
+    if times.is_empty() {
+        return 0;
+    }
+    times.sort();
+    times[times.len() / 2]
+}
+
+/// Checks against the chain the block would extend, at `height`. `history`
+/// holds the headers right before it, oldest first and ending with its
+/// parent; at least `MEDIAN_TIME_SPAN` and what retargeting needs, unless the
+/// chain is shorter. `view` is the UTXO set as of the parent. Returns the fees
+/// collected.
+pub fn check_block_context<V: UtxoView>(block: &Block, history: &[BlockHeader], height: u64, view: &V, params: &ChainParams) -> Result<u64, BlockError> {
+    check_header_context(&block.header, history, height, params)?;
+    check_block_txs(block, view, height, params)
+}
+
+/// Header part of `check_block_context`
+pub fn check_header_context(header: &BlockHeader, history: &[BlockHeader], height: u64, params: &ChainParams) -> Result<(), BlockError> {
+    match history.last() {
+        Some(parent) if parent.hash() == header.prev_hash => (),
+        _ => return Err(BlockError::UnknownParent(header.prev_hash))
+    }
+
+    let median = median_time_past(history);
+    if header.timestamp <= median {
+        return Err(BlockError::TimeTooOld { median: median, timestamp: header.timestamp });
+    }
+    if !retarget::check_bits(&params.retarget, height, history, header) {
+        let expected = retarget::next_bits(&params.retarget, height, history);
+        return Err(BlockError::BadTarget { expected: expected, actual: header.bits });
+    }
+    Ok(())
+}
+
+/// Check every transaction of a block at `height` against the UTXO set it
+/// would be connected to; returns the fees collected
+pub fn check_block_txs<V: UtxoView>(block: &Block, view: &V, height: u64, params: &ChainParams) -> Result<u64, BlockError> {
+    let coinbase = match block.txs.first() {
+        Some(tx) if tx.is_coinbase() => tx,
+        _ => return Err(BlockError::NoCoinbase)
+    };
+    let minted = check_tx(coinbase).map_err(|e| BlockError::Tx(0, e))?;
+    let coinbase_height = coinbase.coinbase_data().unwrap().height;
+    if coinbase_height != height {
+        return Err(BlockError::CoinbaseHeight { expected: height, actual: coinbase_height });
+    }
+
+    // Transactions may spend outputs of those before them in the block
+    let mut overlay = UtxoOverlay::new(view);
+    overlay.apply(coinbase, height);
+    let mut fees = 0u64;
+    for (i, tx) in block.txs.iter().enumerate().skip(1) {
+        let fee = check_tx_inputs(tx, &overlay, height, params).map_err(|e| BlockError::Tx(i, e))?;
+        fees = fees.checked_add(fee).ok_or(BlockError::FeeOverflow)?;
+        overlay.apply(tx, height);
+    }
+
+    let allowed = params.block_reward(height).checked_add(fees).ok_or(BlockError::FeeOverflow)?;
+    if minted > allowed {
+        return Err(BlockError::CoinbaseOverpays { allowed: allowed, actual: minted });
+    }
+    Ok(fees)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use miner;
+    use protocol;
+    use test_util::{funded, keypair, owner, spend};
+    use utxo::UtxoSet;
+
+    #[test]
+    fn valid() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[30, 20]);
+        let tx = spend(1, &[(funding, 0, 30), (funding, 1, 20)], &[45]);
+        assert_eq!(check_tx_inputs(&tx, &set, 2, &params), Ok(5));
+
+        // Any signature hash type goes, as long as it applies
+        let amounts = [30, 20];
+        let mut partial = tx.clone();
+        assert!(partial.sign_input(0, &keypair(1), &amounts, protocol::SigHashType::new(protocol::SigHashBase::None, true)));
+        assert_eq!(check_tx_inputs(&partial, &set, 2, &params), Ok(5));
+        let single = protocol::SigHashType::new(protocol::SigHashBase::Single, false);
+        assert!(!partial.sign_input(1, &keypair(1), &amounts, single));
+        assert_eq!(check_tx_inputs(&partial, &set, 2, &params), Err(TxError::BadSigHashType(1)));
+    }
+
+    #[test]
+    fn owners() {
+        let params = ChainParams::regtest();
+        let mut funding = Transaction::new();
+        funding.add_output(30, owner(1));
+        funding.add_output(20, owner(2));
+        let hash = funding.hash();
+        let mut set = UtxoSet::new();
+        set.connect_block(&Block::new(BlockHash::new(), 0, 0, vec![funding]), 1).unwrap();
+
+        // Coins of two keys in one transaction, each signing its own input
+        let mut tx = Transaction::new();
+        tx.add_input(hash, 0, keypair(1).public);
+        tx.add_input(hash, 1, keypair(2).public);
+        tx.add_output(50, owner(3));
+        tx.sign(keypair(1), &[30, 20]);
+        assert_eq!(check_tx_inputs(&tx, &set, 2, &params), Err(TxError::BadSignature(1)));
+        assert!(!tx.sign_input(1, &keypair(1), &[30, 20], protocol::SigHashType::ALL));
+        tx.sign(keypair(2), &[30, 20]);
+        assert_eq!(check_tx_inputs(&tx, &set, 2, &params), Ok(0));
+
+        // Keys must match the outputs they spend
+        let mut swapped = Transaction::new();
+        swapped.add_input(hash, 0, keypair(2).public);
+        swapped.add_output(30, owner(3));
+        swapped.sign(keypair(2), &[30]);
+        assert_eq!(check_tx_inputs(&swapped, &set, 2, &params),
+                   Err(TxError::WrongOwner(OutPoint { tx: hash, index: 0 })));
+    }
+
+    #[test]
+    fn invalid() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[30, u64::max_value()]);
+        let check = |tx: &Transaction| check_tx_inputs(tx, &set, 2, &params);
+
+        assert_eq!(check_tx(&spend(1, &[], &[1])), Err(TxError::NoInputs));
+        assert_eq!(check_tx(&spend(1, &[(funding, 0, 30)], &[])), Err(TxError::NoOutputs));
+        assert_eq!(check_tx(&spend(1, &[(funding, 0, 30)], &[1; 257])), Err(TxError::TooManyOutputs(257)));
+        assert_eq!(check_tx(&spend(1, &[(funding, 0, 30)], &[1; 256])), Ok(256));
+        assert_eq!(check_tx(&spend(1, &[(funding, 0, 30), (funding, 0, 30)], &[1])),
+                   Err(TxError::DuplicateInput(OutPoint { tx: funding, index: 0 })));
+        assert_eq!(check_tx(&spend(1, &[(funding, 0, 30)], &[u64::max_value(), 1])), Err(TxError::OutputOverflow));
+
+        assert_eq!(check(&spend(1, &[(funding, 2, 0)], &[1])),
+                   Err(TxError::MissingInput(OutPoint { tx: funding, index: 2 })));
+        assert_eq!(check(&spend(2, &[(funding, 0, 30)], &[1])),
+                   Err(TxError::WrongOwner(OutPoint { tx: funding, index: 0 })));
+        assert_eq!(check(&spend(1, &[(funding, 0, 30), (funding, 1, u64::max_value())], &[1])), Err(TxError::InputOverflow));
+        assert_eq!(check(&spend(1, &[(funding, 0, 30)], &[31])),
+                   Err(TxError::InsufficientFunds { inputs: 30, outputs: 31 }));
+
+        // Only the owner can sign, and not before tampering
+        let mut forged = Transaction::new();
+        forged.add_input(funding, 0, keypair(1).public);
+        forged.add_output(10, Address::new());
+        forged.sign(keypair(2), &[30]);
+        assert_eq!(check(&forged), Err(TxError::BadSignature(0)));
+
+        let mut tampered = spend(1, &[(funding, 0, 30)], &[10]);
+        tampered.add_output(10, Address::new());
+        assert_eq!(check(&tampered), Err(TxError::BadSignature(0)));
+
+        // Coinbases only belong at the start of a block
+        let coinbase = Transaction::coinbase(2, 10, owner(1));
+        assert_eq!(check_tx(&coinbase), Ok(10));
+        assert_eq!(check(&coinbase), Err(TxError::UnexpectedCoinbase));
+        let mut malformed = Transaction::coinbase(2, 10, owner(1));
+        malformed.add_input(funding, 0, keypair(1).public);
+        assert_eq!(check_tx(&malformed), Err(TxError::MalformedCoinbase));
+    }
+
+    #[test]
+    fn maturity() {
+        let params = ChainParams::regtest();
+        let coinbase = Transaction::coinbase(1, 50, owner(1));
+        let hash = coinbase.hash();
+        let mut set = UtxoSet::new();
+        set.connect_block(&Block::new(BlockHash::new(), 0, 0, vec![coinbase]), 1).unwrap();
+
+        let tx = spend(1, &[(hash, 0, 50)], &[50]);
+        let maturity = params.coinbase_maturity;
+        assert_eq!(check_tx_inputs(&tx, &set, maturity, &params),
+                   Err(TxError::ImmatureCoinbase(OutPoint { tx: hash, index: 0 })));
+        assert_eq!(check_tx_inputs(&tx, &set, 1 + maturity, &params), Ok(0));
+    }
+
+    #[test]
+    fn block() {
+        let params = ChainParams::regtest();
+        let (set, funding) = funded(1, &[30]);
+        let reward = params.block_reward(2);
+        let block = |txs: Vec<Transaction>| Block::new(BlockHash::new(), 0, 0, txs);
+
+        // The second transaction spends the first; fees go to the miner
+        let a = spend(1, &[(funding, 0, 30)], &[25]);
+        let b = spend(1, &[(a.hash(), 0, 25)], &[20]);
+        let coinbase = |amount| Transaction::coinbase(2, amount, owner(2));
+        let valid = block(vec![coinbase(reward + 10), a.clone(), b.clone()]);
+        assert_eq!(check_block_txs(&valid, &set, 2, &params), Ok(10));
+
+        assert_eq!(check_block_txs(&block(vec![coinbase(reward + 11), a.clone(), b.clone()]), &set, 2, &params),
+                   Err(BlockError::CoinbaseOverpays { allowed: reward + 10, actual: reward + 11 }));
+        assert_eq!(check_block_txs(&valid, &set, 3, &params),
+                   Err(BlockError::CoinbaseHeight { expected: 3, actual: 2 }));
+        assert_eq!(check_block_txs(&block(vec![a.clone(), b.clone()]), &set, 2, &params), Err(BlockError::NoCoinbase));
+        assert_eq!(check_block_txs(&block(vec![coinbase(reward), coinbase(reward)]), &set, 2, &params),
+                   Err(BlockError::Tx(1, TxError::UnexpectedCoinbase)));
+        let a_hash = a.hash();
+        assert_eq!(check_block_txs(&block(vec![coinbase(reward), b, a]), &set, 2, &params),
+                   Err(BlockError::Tx(1, TxError::MissingInput(OutPoint { tx: a_hash, index: 0 }))));
+    }
+
+    /// Find a nonce meeting the block's target
+    fn solve(mut block: Block) -> Block {
+        block.header.nonce = miner::search(&block.header, 0, 1, &AtomicBool::new(false)).unwrap();
+        block
+    }
+
+    #[test]
+    fn context_free() {
+        let now = protocol::unix_time();
+        let coinbase = |height| Transaction::coinbase(height, 50, owner(1));
+        let block = |txs| solve(Block::new(BlockHash::new(), now, pow::REGTEST_BITS, txs));
+        assert_eq!(check_block(&block(vec![coinbase(1)]), now), Ok(()));
+
+        assert_eq!(check_block(&block(vec![]), now), Err(BlockError::NoCoinbase));
+        assert_eq!(check_block(&block(vec![coinbase(1), coinbase(2)]), now), Err(BlockError::ExtraCoinbase(1)));
+        let (_, funding) = funded(1, &[10]);
+        let tx = spend(1, &[(funding, 0, 10)], &[10]);
+        assert_eq!(check_block(&block(vec![coinbase(1), tx.clone(), tx.clone()]), now),
+                   Err(BlockError::DuplicateTx(tx.hash())));
+
+        let mut tampered = Block::new(BlockHash::new(), now, pow::REGTEST_BITS, vec![coinbase(1)]);
+        tampered.header.merkle_root = TxHash::new();
+        assert_eq!(check_block(&solve(tampered), now), Err(BlockError::BadMerkleRoot));
+
+        let mut unsolved = block(vec![coinbase(1)]);
+        while pow::check_proof(&unsolved.hash(), unsolved.header.bits) {
+            unsolved.header.nonce += 1;
+        }
+        assert_eq!(check_block(&unsolved, now), Err(BlockError::BadProofOfWork));
+
+        let future = block(vec![coinbase(1)]);
+        assert_eq!(check_block(&future, now - MAX_FUTURE_BLOCK_TIME), Ok(()));
+        assert_eq!(check_block(&future, now - MAX_FUTURE_BLOCK_TIME - 1), Err(BlockError::TimeTooNew(now)));
+
+        let huge = block((0..20000).map(coinbase).collect());
+        match check_block(&huge, now) {
+            Err(BlockError::TooLarge(size)) => assert!(size > MAX_BLOCK_SIZE),
+            r => panic!("expected TooLarge, got {:?}", r)
+        }
+    }
+
+    #[test]
+    fn contextual() {
+        let params = ChainParams::regtest();
+        let set = UtxoSet::new();
+        let genesis = params.genesis.header.clone();
+        let history = vec![genesis.clone()];
+        let reward = params.block_reward(1);
+        let block = |prev, timestamp, bits| {
+            Block::new(prev, timestamp, bits, vec![Transaction::coinbase(1, reward, owner(1))])
+        };
+        let valid = block(genesis.hash(), genesis.timestamp + 1, pow::REGTEST_BITS);
+        assert_eq!(check_block_context(&valid, &history, 1, &set, &params), Ok(0));
+
+        assert_eq!(check_block_context(&block(BlockHash::new(), genesis.timestamp + 1, pow::REGTEST_BITS), &history, 1, &set, &params),
+                   Err(BlockError::UnknownParent(BlockHash::new())));
+        assert_eq!(check_block_context(&block(genesis.hash(), genesis.timestamp, pow::REGTEST_BITS), &history, 1, &set, &params),
+                   Err(BlockError::TimeTooOld { median: genesis.timestamp, timestamp: genesis.timestamp }));
+        assert_eq!(check_block_context(&block(genesis.hash(), genesis.timestamp + 1, 0x1f00ffff), &history, 1, &set, &params),
+                   Err(BlockError::BadTarget { expected: pow::REGTEST_BITS, actual: 0x1f00ffff }));
+
+        // The median is taken over the last blocks only, whatever their order
+        let mut history = Vec::new();
+        for &timestamp in [1, 9, 2, 8, 3, 7, 4, 6, 5, 100, 101, 102].iter() {
+            let mut header = genesis.clone();
+            header.timestamp = timestamp;
+            history.push(header);
+        }
+        assert_eq!(median_time_past(&history), 7);
+        assert_eq!(median_time_past(&[]), 0);
+
+        assert_eq!(BlockError::UnknownParent(BlockHash::new()).penalty(), 0);
+        assert_eq!(BlockError::BadMerkleRoot.penalty(), PENALTY_INVALID);
+    }
+}
@@ -0,0 +1,110 @@
+// Wallet.
+//
+// Builds transactions on the account's behalf. For now, that's bumping the
+// fee of a transaction stuck in the mempool: the bump spends the same inputs,
+// so it replaces the original (c.f. `mempool`), and pays the extra fee out of
+// the change, i.e. the first output paying back to the account. Only
+// transactions whose every input the account owns can be bumped, as the bump
+// must be signed anew.
+//
+use std::error::Error;
+use std::fmt;
+use ed25519_dalek::Keypair;
+use protocol::{Address, Transaction};
+
+#[derive(Debug, PartialEq)]
+pub enum WalletError {
+    /// The transaction spends coins the account doesn't own
+    NotOwned,
+    /// No output pays back to the account
+    NoChange,
+    /// The change can't cover the extra fee
+    InsufficientChange { needed: u64, available: u64 },
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &WalletError::NotOwned => write!(f, "transaction spends coins not owned by this account"),
+            &WalletError::NoChange => write!(f, "transaction has no change output"),
+            &WalletError::InsufficientChange { needed, available } =>
+                write!(f, "change of {} can't cover {} more in fees", available, needed),
+        }
+    }
+}
+
+impl Error for WalletError {
+    fn description(&self) -> &str {
+        "wallet error"
+    }
+}
+
+/// Copy of `tx`, which pays `fee`, paying `new_fee` instead; signed by
+/// `keypair`, which must own every input of `tx`. `amounts` are those of the outputs
+/// `tx` spends.
+pub fn bump_fee(tx: &Transaction, fee: u64, new_fee: u64, keypair: Keypair, amounts: &[u64]) -> Result<Transaction, WalletError> {
+    if tx.inputs().iter().any(|input| input.owner != keypair.public) {
+        return Err(WalletError::NotOwned);
+    }
+    let owner = Address::from_public_key(&keypair.public);
+    let change = match tx.outputs().iter().position(|output| output.creditor == owner) {
+        Some(i) => i,
+        None => return Err(WalletError::NoChange)
+    };
+    let needed = new_fee.saturating_sub(fee);
+    let available = tx.outputs()[change].amount;
+    if needed > available {
+        return Err(WalletError::InsufficientChange { needed: needed, available: available });
+    }
+
+    // A smaller amount never encodes larger, so the fee rate goes up too
+    let mut bumped = Transaction::new();
+    for input in tx.inputs() {
+        bumped.add_input(input.tx, input.index, input.owner);
+    }
+    for (i, output) in tx.outputs().iter().enumerate() {
+        let amount = if i == change { available - needed } else { output.amount };
+        bumped.add_output(amount, output.creditor);
+    }
+    bumped.sign(keypair, amounts);
+    Ok(bumped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chainparams::ChainParams;
+    use mempool::{Mempool, DEFAULT_EXPIRY, DEFAULT_MAX_SIZE};
+    use test_util::{funded, keypair, owner};
+
+    #[test]
+    fn bump() {
+        let params = ChainParams::regtest();
+        let (me, other) = (owner(1), owner(2));
+        let (set, funding) = funded(1, &[100]);
+
+        let mut tx = Transaction::new();
+        tx.add_input(funding, 0, keypair(1).public);
+        tx.add_output(50, other);
+        tx.add_output(49, me);
+        tx.sign(keypair(1), &[100]);
+        let mut pool = Mempool::new(DEFAULT_MAX_SIZE, DEFAULT_EXPIRY, 0);
+        pool.add(tx.clone(), &set, 2, &params, 0).unwrap();
+
+        // The bump replaces the original, paying the receiver the same
+        let new_fee = pool.replacement_fee(&tx.hash(), 100).unwrap();
+        let bumped = bump_fee(&tx, 1, new_fee, keypair(1), &[100]).unwrap();
+        assert_eq!(bumped.outputs()[0].amount, 50);
+        assert_eq!(bumped.outputs()[1].amount, 50 - new_fee);
+        assert!(pool.add(bumped.clone(), &set, 2, &params, 0).is_ok());
+        assert!(!pool.contains(&tx.hash()));
+        assert!(pool.entry(&bumped.hash()).unwrap().fee_rate() >= 100);
+
+        assert_eq!(bump_fee(&tx, 1, 60, keypair(1), &[100]).err(), Some(WalletError::InsufficientChange { needed: 59, available: 49 }));
+        assert_eq!(bump_fee(&tx, 1, 2, keypair(2), &[100]).err(), Some(WalletError::NotOwned));
+        let mut gift = Transaction::new();
+        gift.add_input(funding, 0, keypair(1).public);
+        gift.add_output(99, other);
+        assert_eq!(bump_fee(&gift, 1, 2, keypair(1), &[100]).err(), Some(WalletError::NoChange));
+    }
+}